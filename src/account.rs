@@ -1,50 +1,55 @@
 //! Account management
 
+use crate::currency::Currency;
 use crate::io;
-use crate::moneys::Moneys;
-use anyhow::Result;
+use crate::moneys::{Moneys, MoneysError};
+use crate::restriction::{Restriction, RestrictionReason};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+type Result<T, E = MoneysError> = std::result::Result<T, E>;
 
 pub type ClientId = u16;
 
-#[derive(Debug, Clone, PartialEq)]
-pub struct Account {
-    client: ClientId,
+/// Money of an account in a single currency
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Balance {
     available: Moneys,
     held: Moneys,
-    locked: bool,
 }
 
-impl Account {
-    pub fn new(client: ClientId) -> Self {
+impl Balance {
+    /// Empty balance with amounts of `scale` decimal places
+    pub fn zero(scale: u8) -> Self {
+        let zero = Moneys::with_scale(0, scale);
         Self {
-            client,
-            available: Moneys::ZERO,
-            held: Moneys::ZERO,
-            locked: false,
+            available: zero,
+            held: zero,
         }
     }
 
-    pub fn client(&self) -> ClientId {
-        self.client
+    pub fn available(&self) -> Moneys {
+        self.available
     }
 
-    pub fn is_locked(&self) -> bool {
-        self.locked
+    pub fn held(&self) -> Moneys {
+        self.held
     }
 
-    #[allow(dead_code)]
-    pub fn unlock(&mut self) {
-        self.locked = false;
+    /// Available and held money together. Operations that add money to the balance refuse to
+    /// overflow the total, so it's always representable and moving money between available
+    /// and held (like resolving a dispute) can't overflow either.
+    pub fn total(&self) -> Result<Moneys> {
+        self.available.add(self.held)
     }
 
     pub fn deposit(&self, amount: Moneys) -> Result<Self> {
+        self.total()?.add(amount)?;
         let new_available = self.available.add(amount)?;
 
         Ok(Self {
-            client: self.client,
             available: new_available,
             held: self.held,
-            locked: self.locked,
         })
     }
 
@@ -52,10 +57,8 @@ impl Account {
         let new_available = self.available.sub(amount)?;
 
         Ok(Self {
-            client: self.client,
             available: new_available,
             held: self.held,
-            locked: self.locked,
         })
     }
 
@@ -64,10 +67,8 @@ impl Account {
         let new_held = self.held.add(amount)?;
 
         Ok(Self {
-            client: self.client,
             available: new_available,
             held: new_held,
-            locked: self.locked,
         })
     }
 
@@ -76,10 +77,8 @@ impl Account {
         let new_held = self.held.sub(amount)?;
 
         Ok(Self {
-            client: self.client,
             available: new_available,
             held: new_held,
-            locked: self.locked,
         })
     }
 
@@ -87,169 +86,462 @@ impl Account {
         let new_held = self.held.sub(amount)?;
 
         Ok(Self {
-            client: self.client,
             available: self.available,
             held: new_held,
-            locked: true,
+        })
+    }
+
+    /// Client disputes withdrawal, the amount is held as a pending credit
+    pub fn dispute_withdrawal(&self, amount: Moneys) -> Result<Self> {
+        self.total()?.add(amount)?;
+        let new_held = self.held.add(amount)?;
+
+        Ok(Self {
+            available: self.available,
+            held: new_held,
+        })
+    }
+
+    /// Withdrawal dispute was found invalid, the pending credit is dropped
+    pub fn resolve_withdrawal(&self, amount: Moneys) -> Result<Self> {
+        let new_held = self.held.sub(amount)?;
+
+        Ok(Self {
+            available: self.available,
+            held: new_held,
+        })
+    }
+
+    /// Withdrawal was reversed, the pending credit is returned to the client
+    pub fn chargeback_withdrawal(&self, amount: Moneys) -> Result<Self> {
+        let new_available = self.available.add(amount)?;
+        let new_held = self.held.sub(amount)?;
+
+        Ok(Self {
+            available: new_available,
+            held: new_held,
         })
     }
 }
 
-impl From<Account> for io::Account {
-    fn from(account: Account) -> Self {
+/// What an account accepts, as output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountStatus {
+    /// Usable, unless locked by a chargeback
+    #[default]
+    Open,
+    /// Restricted by an operator
+    Restricted,
+    /// Permanently out of use
+    Closed,
+}
+
+impl AccountStatus {
+    pub fn is_open(&self) -> bool {
+        *self == AccountStatus::Open
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccountStatus::Open => "open",
+            AccountStatus::Restricted => "restricted",
+            AccountStatus::Closed => "closed",
+        }
+    }
+}
+
+/// Account of a client with a balance for every currency the client used
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Account {
+    client: ClientId,
+    /// Ordered by currency, so that output is deterministic
+    balances: BTreeMap<Currency, Balance>,
+    /// At most one restriction for every reason
+    restrictions: BTreeMap<RestrictionReason, Restriction>,
+    closed: bool,
+}
+
+impl Account {
+    pub fn new(client: ClientId) -> Self {
         Self {
-            client: account.client,
-            available: account.available.into(),
-            held: account.held.into(),
-            total: f64::from(account.available) + f64::from(account.held),
-            locked: account.locked,
+            client,
+            balances: BTreeMap::new(),
+            restrictions: BTreeMap::new(),
+            closed: false,
+        }
+    }
+
+    pub fn client(&self) -> ClientId {
+        self.client
+    }
+
+    /// Whether the account is restricted because of a chargeback
+    pub fn is_locked(&self) -> bool {
+        self.restrictions
+            .contains_key(&RestrictionReason::Chargeback)
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Closed, restricted by an operator, or open
+    pub fn status(&self) -> AccountStatus {
+        if self.closed {
+            AccountStatus::Closed
+        } else if self
+            .restrictions
+            .keys()
+            .any(|reason| *reason != RestrictionReason::Chargeback)
+        {
+            AccountStatus::Restricted
+        } else {
+            AccountStatus::Open
         }
     }
+
+    /// Restriction set for `reason`, if any
+    pub fn restriction(&self, reason: RestrictionReason) -> Option<Restriction> {
+        self.restrictions.get(&reason).copied()
+    }
+
+    /// All restrictions with their reasons
+    pub fn restrictions(&self) -> impl Iterator<Item = (RestrictionReason, Restriction)> + '_ {
+        self.restrictions
+            .iter()
+            .map(|(reason, restriction)| (*reason, *restriction))
+    }
+
+    /// Balance in `currency`, if the client ever had any money in it
+    pub fn balance(&self, currency: &Currency) -> Option<&Balance> {
+        self.balances.get(currency)
+    }
+
+    /// Balances in all currencies, ordered by currency
+    pub fn balances(&self) -> impl Iterator<Item = (&Currency, &Balance)> {
+        self.balances.iter()
+    }
+
+    /// Total of the balance in `currency` (see `Balance::total`)
+    pub fn total(&self, currency: &Currency) -> Result<Moneys> {
+        self.balance(currency)
+            .map_or(Ok(Moneys::ZERO), Balance::total)
+    }
+
+    /// Restrict account for `reason`, replacing restriction set for it before
+    pub fn restrict(&mut self, reason: RestrictionReason, restriction: Restriction) {
+        self.restrictions.insert(reason, restriction);
+    }
+
+    /// Lift restriction set for `reason`, returns it if there was any
+    pub fn lift(&mut self, reason: RestrictionReason) -> Option<Restriction> {
+        self.restrictions.remove(&reason)
+    }
+
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
+    /// Account with `operation` applied to the balance in `currency`. Balance that doesn't
+    /// exist yet starts empty with the scale of `amount`.
+    fn update(
+        &self,
+        currency: &Currency,
+        amount: Moneys,
+        operation: fn(&Balance, Moneys) -> Result<Balance>,
+    ) -> Result<Self> {
+        let balance = self
+            .balance(currency)
+            .copied()
+            .unwrap_or_else(|| Balance::zero(amount.scale()));
+        let mut account = self.clone();
+        account
+            .balances
+            .insert(currency.clone(), operation(&balance, amount)?);
+        Ok(account)
+    }
+
+    pub fn deposit(&self, currency: &Currency, amount: Moneys) -> Result<Self> {
+        self.update(currency, amount, Balance::deposit)
+    }
+
+    pub fn withdraw(&self, currency: &Currency, amount: Moneys) -> Result<Self> {
+        self.update(currency, amount, Balance::withdraw)
+    }
+
+    pub fn dispute(&self, currency: &Currency, amount: Moneys) -> Result<Self> {
+        self.update(currency, amount, Balance::dispute)
+    }
+
+    pub fn resolve(&self, currency: &Currency, amount: Moneys) -> Result<Self> {
+        self.update(currency, amount, Balance::resolve)
+    }
+
+    /// Charged back deposit locks the whole account with `restriction`
+    pub fn chargeback(
+        &self,
+        currency: &Currency,
+        amount: Moneys,
+        restriction: Restriction,
+    ) -> Result<Self> {
+        let mut account = self.update(currency, amount, Balance::chargeback)?;
+        account.restrict(RestrictionReason::Chargeback, restriction);
+        Ok(account)
+    }
+
+    pub fn dispute_withdrawal(&self, currency: &Currency, amount: Moneys) -> Result<Self> {
+        self.update(currency, amount, Balance::dispute_withdrawal)
+    }
+
+    pub fn resolve_withdrawal(&self, currency: &Currency, amount: Moneys) -> Result<Self> {
+        self.update(currency, amount, Balance::resolve_withdrawal)
+    }
+
+    pub fn chargeback_withdrawal(&self, currency: &Currency, amount: Moneys) -> Result<Self> {
+        self.update(currency, amount, Balance::chargeback_withdrawal)
+    }
+
+    /// Output rows of the account, one per currency
+    pub fn rows(&self) -> Result<Vec<io::Account>> {
+        self.balances
+            .iter()
+            .map(|(currency, balance)| {
+                Ok(io::Account {
+                    client: self.client,
+                    currency: currency.clone(),
+                    available: balance.available,
+                    held: balance.held,
+                    total: balance.total()?,
+                    locked: self.is_locked(),
+                    status: self.status(),
+                })
+            })
+            .collect()
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    const EMPTY_ACCOUNT: Account = Account {
-        client: 317,
+
+    /// Largest value with the default scale
+    const MAX: Moneys = Moneys::new(u128::MAX);
+
+    const EMPTY_BALANCE: Balance = Balance {
         available: Moneys::new(0),
         held: Moneys::new(0),
-        locked: false,
     };
-    const DEPOSITED_ACCOUNT: Account = Account {
-        client: 317,
+    const DEPOSITED_BALANCE: Balance = Balance {
         available: Moneys::new(300),
         held: Moneys::new(0),
-        locked: false,
     };
-    const LOCKED_ACCOUNT: Account = Account {
-        client: 317,
+    const CHARGED_BACK_BALANCE: Balance = Balance {
         available: Moneys::new(100),
         held: Moneys::new(0),
-        locked: true,
     };
-    const HELD_ACCOUNT: Account = Account {
-        client: 317,
+    const HELD_BALANCE: Balance = Balance {
         available: Moneys::new(100),
         held: Moneys::new(200),
-        locked: false,
     };
-    const HELD_DEPOSIT_ACCOUNT: Account = Account {
-        client: 317,
+    const HELD_DEPOSIT_BALANCE: Balance = Balance {
         available: Moneys::new(500),
         held: Moneys::new(200),
-        locked: false,
-    };
-    const MAXED_ACCOUNT: Account = Account {
-        client: 317,
-        available: Moneys::MAX,
-        held: Moneys::new(0),
-        locked: false,
     };
-    const MAXED_DISPUTED_ACCOUNT: Account = Account {
-        client: 317,
+    const PENDING_CREDIT_BALANCE: Balance = Balance {
         available: Moneys::new(0),
-        held: Moneys::MAX,
-        locked: false,
+        held: Moneys::new(200),
     };
-    const MAXED_DISPUTED_DEPOSITED_ACCOUNT: Account = Account {
-        client: 317,
-        available: Moneys::MAX,
-        held: Moneys::MAX,
-        locked: false,
+    const CREDITED_BALANCE: Balance = Balance {
+        available: Moneys::new(200),
+        held: Moneys::new(0),
     };
-    const MAXED_DISPUTED_LOCKED_ACCOUNT: Account = Account {
-        client: 317,
-        available: Moneys::MAX,
+    const MAXED_BALANCE: Balance = Balance {
+        available: MAX,
         held: Moneys::new(0),
-        locked: true,
+    };
+    const MAXED_DISPUTED_BALANCE: Balance = Balance {
+        available: Moneys::new(0),
+        held: MAX,
     };
 
     #[test]
     fn init() {
-        assert_eq!(Account::new(317), EMPTY_ACCOUNT);
+        assert_eq!(Balance::zero(4), EMPTY_BALANCE);
+        let account = Account::new(317);
+        assert_eq!(account.client(), 317);
+        assert_eq!(account.balances().count(), 0);
+        assert_eq!(account.total(&Currency::default()), Ok(Moneys::ZERO));
     }
 
     #[test]
     fn withdraw() {
         // Regular withdraw
         assert_eq!(
-            DEPOSITED_ACCOUNT.withdraw(Moneys::new(300)).unwrap(),
-            EMPTY_ACCOUNT
+            DEPOSITED_BALANCE.withdraw(Moneys::new(300)).unwrap(),
+            EMPTY_BALANCE
         );
-        assert!(DEPOSITED_ACCOUNT.withdraw(Moneys::new(301)).is_err());
+        assert!(DEPOSITED_BALANCE.withdraw(Moneys::new(301)).is_err());
 
         // Max limits
-        assert!(DEPOSITED_ACCOUNT.withdraw(Moneys::MAX).is_err());
-        assert!(EMPTY_ACCOUNT.withdraw(Moneys::MAX).is_err());
-        assert!(EMPTY_ACCOUNT.withdraw(Moneys::new(1)).is_err());
-        assert_eq!(MAXED_ACCOUNT.withdraw(Moneys::MAX).unwrap(), EMPTY_ACCOUNT);
+        assert!(DEPOSITED_BALANCE.withdraw(MAX).is_err());
+        assert!(EMPTY_BALANCE.withdraw(MAX).is_err());
+        assert!(EMPTY_BALANCE.withdraw(Moneys::new(1)).is_err());
+        assert_eq!(MAXED_BALANCE.withdraw(MAX).unwrap(), EMPTY_BALANCE);
     }
 
     #[test]
     fn deposit() {
         // Regular deposits
         assert_eq!(
-            EMPTY_ACCOUNT.deposit(Moneys::new(300)).unwrap(),
-            DEPOSITED_ACCOUNT
+            EMPTY_BALANCE.deposit(Moneys::new(300)).unwrap(),
+            DEPOSITED_BALANCE
         );
-        assert_eq!(EMPTY_ACCOUNT.deposit(Moneys::MAX).unwrap(), MAXED_ACCOUNT);
+        assert_eq!(EMPTY_BALANCE.deposit(MAX).unwrap(), MAXED_BALANCE);
 
         // Held deposits
         assert_eq!(
-            HELD_ACCOUNT.deposit(Moneys::new(400)).unwrap(),
-            HELD_DEPOSIT_ACCOUNT
+            HELD_BALANCE.deposit(Moneys::new(400)).unwrap(),
+            HELD_DEPOSIT_BALANCE
         );
 
         // Max limits
-        assert!(MAXED_ACCOUNT.deposit(Moneys::new(1)).is_err());
-        assert!(MAXED_ACCOUNT.deposit(Moneys::MAX).is_err());
+        assert!(MAXED_BALANCE.deposit(Moneys::new(1)).is_err());
+        assert!(MAXED_BALANCE.deposit(MAX).is_err());
     }
 
     #[test]
     fn disputes() {
         assert_eq!(
-            DEPOSITED_ACCOUNT.dispute(Moneys::new(200)).unwrap(),
-            HELD_ACCOUNT
+            DEPOSITED_BALANCE.dispute(Moneys::new(200)).unwrap(),
+            HELD_BALANCE
         );
         assert_eq!(
-            HELD_ACCOUNT.resolve(Moneys::new(200)).unwrap(),
-            DEPOSITED_ACCOUNT
+            HELD_BALANCE.resolve(Moneys::new(200)).unwrap(),
+            DEPOSITED_BALANCE
         );
         assert_eq!(
-            HELD_ACCOUNT.chargeback(Moneys::new(200)).unwrap(),
-            LOCKED_ACCOUNT
+            HELD_BALANCE.chargeback(Moneys::new(200)).unwrap(),
+            CHARGED_BACK_BALANCE
         );
-        assert!(HELD_ACCOUNT.chargeback(Moneys::new(201)).is_err());
-        assert!(HELD_ACCOUNT.resolve(Moneys::new(201)).is_err());
-        assert_eq!(HELD_ACCOUNT.resolve(Moneys::new(0)).unwrap(), HELD_ACCOUNT);
+        assert!(HELD_BALANCE.chargeback(Moneys::new(201)).is_err());
+        assert!(HELD_BALANCE.resolve(Moneys::new(201)).is_err());
+        assert_eq!(HELD_BALANCE.resolve(Moneys::new(0)).unwrap(), HELD_BALANCE);
 
         // Max limits
+        assert_eq!(MAXED_BALANCE.dispute(MAX).unwrap(), MAXED_DISPUTED_BALANCE);
+
+        // Total can't exceed the limit, so resolving at the limit works
         assert_eq!(
-            MAXED_ACCOUNT.dispute(Moneys::MAX).unwrap(),
-            MAXED_DISPUTED_ACCOUNT
+            MAXED_DISPUTED_BALANCE.deposit(Moneys::new(1)),
+            Err(MoneysError::Overflow)
         );
+        assert_eq!(MAXED_DISPUTED_BALANCE.resolve(MAX).unwrap(), MAXED_BALANCE);
         assert_eq!(
-            MAXED_DISPUTED_ACCOUNT.deposit(Moneys::MAX).unwrap(),
-            MAXED_DISPUTED_DEPOSITED_ACCOUNT
+            MAXED_DISPUTED_BALANCE.chargeback(MAX).unwrap(),
+            EMPTY_BALANCE
         );
-        assert!(MAXED_DISPUTED_DEPOSITED_ACCOUNT
-            .resolve(Moneys::new(1))
-            .is_err());
+    }
+
+    #[test]
+    fn withdrawal_disputes() {
         assert_eq!(
-            MAXED_DISPUTED_DEPOSITED_ACCOUNT
-                .chargeback(Moneys::MAX)
+            EMPTY_BALANCE.dispute_withdrawal(Moneys::new(200)).unwrap(),
+            PENDING_CREDIT_BALANCE
+        );
+        assert_eq!(
+            PENDING_CREDIT_BALANCE
+                .resolve_withdrawal(Moneys::new(200))
+                .unwrap(),
+            EMPTY_BALANCE
+        );
+        assert_eq!(
+            PENDING_CREDIT_BALANCE
+                .chargeback_withdrawal(Moneys::new(200))
                 .unwrap(),
-            MAXED_DISPUTED_LOCKED_ACCOUNT
+            CREDITED_BALANCE
         );
+        assert!(PENDING_CREDIT_BALANCE
+            .resolve_withdrawal(Moneys::new(201))
+            .is_err());
+        assert!(PENDING_CREDIT_BALANCE
+            .chargeback_withdrawal(Moneys::new(201))
+            .is_err());
+
+        // Max limits
+        assert!(MAXED_DISPUTED_BALANCE
+            .dispute_withdrawal(Moneys::new(1))
+            .is_err());
+        assert_eq!(
+            MAXED_BALANCE.dispute_withdrawal(Moneys::new(1)),
+            Err(MoneysError::Overflow)
+        );
+    }
+
+    #[test]
+    fn total() {
+        assert_eq!(HELD_BALANCE.total().unwrap(), Moneys::new(300));
+        assert_eq!(MAXED_DISPUTED_BALANCE.total().unwrap(), MAX);
+    }
+
+    #[test]
+    fn currencies() {
+        let usd = Currency::new("USD").unwrap();
+        let jpy = Currency::new("JPY").unwrap();
+        let account = Account::new(317)
+            .deposit(&usd, Moneys::with_scale(150, 2))
+            .unwrap()
+            .deposit(&jpy, Moneys::with_scale(1000, 0))
+            .unwrap();
+        assert!(account.withdraw(&usd, Moneys::with_scale(200, 2)).is_err());
+        let account = account.dispute(&jpy, Moneys::with_scale(400, 0)).unwrap();
+
+        let rows = account.rows().unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(rows[0].currency, jpy);
+        assert_eq!(rows[0].available.to_string(), "600");
+        assert_eq!(rows[0].held.to_string(), "400");
+        assert_eq!(rows[1].currency, usd);
+        assert_eq!(rows[1].held.to_string(), "0.00");
+        assert_eq!(rows[1].total.to_string(), "1.50");
+
+        // Chargeback in any currency locks the whole account
+        let account = account
+            .chargeback(&jpy, Moneys::with_scale(400, 0), Restriction::Frozen)
+            .unwrap();
+        assert!(account.is_locked());
+        assert!(account.rows().unwrap().iter().all(|row| row.locked));
     }
 
     #[test]
     fn locking() {
-        assert!(!EMPTY_ACCOUNT.is_locked());
-        assert!(LOCKED_ACCOUNT.is_locked());
-        let mut account = LOCKED_ACCOUNT.clone();
-        account.unlock();
+        let account = Account::new(317)
+            .deposit(&Currency::default(), Moneys::new(300))
+            .unwrap();
+        assert!(!account.is_locked());
+        let mut account = account
+            .dispute(&Currency::default(), Moneys::new(200))
+            .unwrap()
+            .chargeback(
+                &Currency::default(),
+                Moneys::new(200),
+                Restriction::DepositsOnly,
+            )
+            .unwrap();
+        assert!(account.is_locked());
+        assert_eq!(account.status(), AccountStatus::Open);
+        assert_eq!(
+            account.balance(&Currency::default()),
+            Some(&CHARGED_BACK_BALANCE)
+        );
+        account.restrict(RestrictionReason::Compliance, Restriction::Frozen);
+        assert_eq!(account.status(), AccountStatus::Restricted);
+        assert_eq!(
+            account.lift(RestrictionReason::Chargeback),
+            Some(Restriction::DepositsOnly)
+        );
         assert!(!account.is_locked());
+        let json = serde_json::to_string(&account).unwrap();
+        assert_eq!(serde_json::from_str::<Account>(&json).unwrap(), account);
     }
 }
@@ -0,0 +1,91 @@
+//! Currencies of accounts
+
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Currency code, three ASCII letters like `USD` or `BTC`, kept upper-case. Commands that don't
+/// name a currency use the default one, the empty code, so that single-currency input works as
+/// it always did.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Currency(String);
+
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("invalid currency code {0:?}, expected three letters like USD")]
+pub struct CurrencyError(String);
+
+impl Currency {
+    /// Currency of `code`, surrounding whitespace and case don't matter
+    pub fn new(code: &str) -> Result<Self, CurrencyError> {
+        let code = code.trim();
+        if !code.is_empty()
+            && (code.len() != 3 || !code.bytes().all(|byte| byte.is_ascii_alphabetic()))
+        {
+            return Err(CurrencyError(code.to_owned()));
+        }
+        Ok(Self(code.to_ascii_uppercase()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// Default currency is shown as such in messages, `as_str` gives the empty code
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_default() {
+            f.write_str("default currency")
+        } else {
+            f.write_str(&self.0)
+        }
+    }
+}
+
+impl FromStr for Currency {
+    type Err = CurrencyError;
+
+    fn from_str(s: &str) -> Result<Self, CurrencyError> {
+        Self::new(s)
+    }
+}
+
+impl TryFrom<String> for Currency {
+    type Error = CurrencyError;
+
+    fn try_from(code: String) -> Result<Self, CurrencyError> {
+        Self::new(&code)
+    }
+}
+
+impl From<Currency> for String {
+    fn from(currency: Currency) -> Self {
+        currency.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn codes() {
+        assert_eq!(Currency::new(" usd"), Currency::new("USD"));
+        assert_eq!(Currency::new("UsD").unwrap().as_str(), "USD");
+        assert!(Currency::new("  ").unwrap().is_default());
+        assert!(Currency::new("US").is_err());
+        assert!(Currency::new("USDT").is_err());
+        assert!(Currency::new("U$D").is_err());
+
+        let currency: Option<Currency> = serde_json::from_str("\" eur\"").unwrap();
+        assert_eq!(currency, Some(Currency::new("EUR").unwrap()));
+        assert!(serde_json::from_str::<Currency>("\"euro\"").is_err());
+    }
+}
@@ -0,0 +1,141 @@
+//! Errors returned for rejected transactions
+
+use crate::account::ClientId;
+use crate::currency::Currency;
+use crate::moneys::MoneysError;
+use crate::processor::TransactionId;
+use crate::restriction::{Restriction, RestrictionReason};
+use thiserror::Error;
+
+/// Reason why processor refused to execute a command
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum TxError {
+    #[error("account {client} is locked (tx {tx})")]
+    LockedAccount { client: ClientId, tx: TransactionId },
+    #[error("transaction {tx} already exists (client {client})")]
+    DuplicateTxId { client: ClientId, tx: TransactionId },
+    #[error("transaction {tx} not found (client {client})")]
+    UnknownTx { client: ClientId, tx: TransactionId },
+    #[error("transaction {tx} belongs to client {owner}, not to client {client}")]
+    ClientMismatch {
+        client: ClientId,
+        tx: TransactionId,
+        owner: ClientId,
+    },
+    #[error("transaction {tx} is in a wrong state for this command (client {client})")]
+    WrongDisputeState { client: ClientId, tx: TransactionId },
+    #[error("transaction {tx} can't be disputed by this amount (client {client})")]
+    InvalidDisputeAmount { client: ClientId, tx: TransactionId },
+    #[error("insufficient funds on account {client} (tx {tx})")]
+    InsufficientFunds { client: ClientId, tx: TransactionId },
+    #[error("balance of account {client} would overflow (tx {tx})")]
+    Overflow { client: ClientId, tx: TransactionId },
+    #[error("transaction {tx} is missing amount of money (client {client})")]
+    MissingAmount { client: ClientId, tx: TransactionId },
+    #[error("transaction {tx} has invalid amount of money (client {client}): {source}")]
+    InvalidAmount {
+        client: ClientId,
+        tx: TransactionId,
+        source: MoneysError,
+    },
+    #[error("transaction {tx} couldn't be stored (client {client}): {message}")]
+    Storage {
+        client: ClientId,
+        tx: TransactionId,
+        message: String,
+    },
+    #[error("transaction {tx} is in {expected}, not in {currency} (client {client})")]
+    CurrencyMismatch {
+        client: ClientId,
+        tx: TransactionId,
+        currency: Currency,
+        expected: Currency,
+    },
+    #[error("no rate for converting {from} to {to} (client {client}, tx {tx})")]
+    NoRate {
+        client: ClientId,
+        tx: TransactionId,
+        from: Currency,
+        to: Currency,
+    },
+    #[error("transaction {tx} can't be disputed (client {client})")]
+    NotDisputable { client: ClientId, tx: TransactionId },
+    #[error("transfer {tx} is missing the recipient (client {client})")]
+    MissingRecipient { client: ClientId, tx: TransactionId },
+    #[error("transfer {tx} is to the same account it is from (client {client})")]
+    SelfTransfer { client: ClientId, tx: TransactionId },
+    #[error("transaction {tx} was dropped with the rest of its rejected batch (client {client})")]
+    BatchAborted { client: ClientId, tx: TransactionId },
+    #[error("account {client} is restricted ({restriction}, {reason}) (tx {tx})")]
+    RestrictedAccount {
+        client: ClientId,
+        tx: TransactionId,
+        restriction: Restriction,
+        reason: RestrictionReason,
+    },
+    #[error("account {client} is closed (tx {tx})")]
+    ClosedAccount { client: ClientId, tx: TransactionId },
+    #[error("admin command {tx} doesn't name the operator (client {client})")]
+    MissingOperator { client: ClientId, tx: TransactionId },
+    #[error("account {client} still has money or open disputes (tx {tx})")]
+    NonZeroBalance { client: ClientId, tx: TransactionId },
+    #[error("client {client} not found (tx {tx})")]
+    UnknownClient { client: ClientId, tx: TransactionId },
+    #[error("account {client} is not locked (tx {tx})")]
+    NotLocked { client: ClientId, tx: TransactionId },
+    #[error("account {client} has no restriction for this reason (tx {tx})")]
+    NotFrozen { client: ClientId, tx: TransactionId },
+    #[error("account {client} has this restriction already (tx {tx})")]
+    AlreadyFrozen { client: ClientId, tx: TransactionId },
+}
+
+impl TxError {
+    /// Stable machine-readable identifier of the error kind
+    pub fn code(&self) -> &'static str {
+        match self {
+            TxError::LockedAccount { .. } => "locked_account",
+            TxError::DuplicateTxId { .. } => "duplicate_tx_id",
+            TxError::UnknownTx { .. } => "unknown_tx",
+            TxError::ClientMismatch { .. } => "client_mismatch",
+            TxError::WrongDisputeState { .. } => "wrong_dispute_state",
+            TxError::InvalidDisputeAmount { .. } => "invalid_dispute_amount",
+            TxError::InsufficientFunds { .. } => "insufficient_funds",
+            TxError::Overflow { .. } => "overflow",
+            TxError::MissingAmount { .. } => "missing_amount",
+            TxError::InvalidAmount { .. } => "invalid_amount",
+            TxError::Storage { .. } => "storage",
+            TxError::CurrencyMismatch { .. } => "currency_mismatch",
+            TxError::NoRate { .. } => "no_rate",
+            TxError::NotDisputable { .. } => "not_disputable",
+            TxError::MissingRecipient { .. } => "missing_recipient",
+            TxError::SelfTransfer { .. } => "self_transfer",
+            TxError::BatchAborted { .. } => "batch_aborted",
+            TxError::RestrictedAccount { .. } => "restricted_account",
+            TxError::ClosedAccount { .. } => "closed_account",
+            TxError::MissingOperator { .. } => "missing_operator",
+            TxError::NonZeroBalance { .. } => "non_zero_balance",
+            TxError::UnknownClient { .. } => "unknown_client",
+            TxError::NotLocked { .. } => "not_locked",
+            TxError::NotFrozen { .. } => "not_frozen",
+            TxError::AlreadyFrozen { .. } => "already_frozen",
+        }
+    }
+
+    /// Attach client and transaction to error coming from balance arithmetic
+    pub(crate) fn from_moneys(error: MoneysError, client: ClientId, tx: TransactionId) -> Self {
+        match error {
+            MoneysError::Underflow => TxError::InsufficientFunds { client, tx },
+            MoneysError::Overflow => TxError::Overflow { client, tx },
+            source => TxError::InvalidAmount { client, tx, source },
+        }
+    }
+}
+
+/// Rejection of a batch of commands, none of them was executed
+#[derive(Debug, Clone, PartialEq, Error)]
+#[error("command {index} of the batch was rejected: {error}")]
+pub struct BatchError {
+    /// Position of the rejected command in the batch
+    pub index: usize,
+    pub error: TxError,
+}
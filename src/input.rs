@@ -0,0 +1,482 @@
+//! Reading of input rows
+
+use crate::error::TxError;
+use crate::io;
+use anyhow::Result;
+use std::cell::RefCell;
+use std::io::{BufRead, BufReader};
+use std::rc::Rc;
+use std::str::FromStr;
+
+/// Format of input commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    /// CSV with a header
+    Csv,
+    /// One JSON object per line, like `{"type":"deposit","client":1,"tx":2,"amount":"1.5"}`
+    Ndjson,
+}
+
+impl InputFormat {
+    /// Guess format from the beginning of the input: NDJSON starts with `{`, anything else is
+    /// taken as CSV
+    fn detect<R: BufRead>(reader: &mut R) -> std::io::Result<Self> {
+        let start = reader.fill_buf()?;
+        Ok(
+            match start.iter().find(|byte| !byte.is_ascii_whitespace()) {
+                Some(b'{') => InputFormat::Ndjson,
+                _ => InputFormat::Csv,
+            },
+        )
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "ndjson" => Ok(InputFormat::Ndjson),
+            _ => Err(format!("unknown input format {:?}", s)),
+        }
+    }
+}
+
+/// Code of rows that couldn't be parsed into a command
+pub(crate) const MALFORMED: &str = "malformed";
+
+/// Command together with where it came from
+#[derive(Debug, Clone)]
+pub(crate) struct Row {
+    pub line: u64,
+    /// Byte offset of the row in the input
+    pub offset: u64,
+    /// The row as it was read
+    pub raw: String,
+    pub command: io::Command,
+}
+
+impl Row {
+    pub fn rejection(&self, error: &TxError) -> io::Rejection {
+        io::Rejection {
+            line: self.line,
+            row: self.raw.clone(),
+            code: error.code().to_owned(),
+            client: Some(self.command.client),
+            tx: Some(self.command.tx),
+            message: error.to_string(),
+        }
+    }
+}
+
+/// Row that couldn't be parsed into a command
+#[derive(Debug, Clone)]
+pub(crate) struct Malformed {
+    pub line: u64,
+    /// Byte offset of the row in the input
+    pub offset: u64,
+    /// The row as it was read
+    pub raw: String,
+    /// Batch ID, if the row has a readable one, so that its batch is rejected too
+    pub batch: Option<String>,
+    pub message: String,
+}
+
+impl Malformed {
+    pub fn rejection(&self) -> io::Rejection {
+        io::Rejection {
+            line: self.line,
+            row: self.raw.clone(),
+            code: MALFORMED.to_owned(),
+            client: None,
+            tx: None,
+            message: format!("malformed row: {}", self.message),
+        }
+    }
+}
+
+/// Row of input, or the reason it couldn't be parsed. Only failures to read the input are
+/// errors, malformed rows are rejected and processing goes on.
+pub(crate) type Parsed = std::result::Result<Row, Malformed>;
+
+fn batch_of(parsed: &Parsed) -> Option<&String> {
+    match parsed {
+        Ok(row) => row.command.batch.as_ref(),
+        Err(malformed) => malformed.batch.as_ref(),
+    }
+}
+
+/// Byte offset of a parsed or malformed row in the input
+pub(crate) fn offset_of(parsed: &Parsed) -> u64 {
+    match parsed {
+        Ok(row) => row.offset,
+        Err(malformed) => malformed.offset,
+    }
+}
+
+/// Rows of `batch` if all of them were parsed. Otherwise the batch is rejected whole:
+/// malformed rows as `malformed`, the others as `batch_aborted`.
+pub(crate) fn parsed(batch: Vec<Parsed>) -> std::result::Result<Vec<Row>, Vec<io::Rejection>> {
+    if batch.iter().all(Result::is_ok) {
+        return Ok(batch.into_iter().filter_map(Result::ok).collect());
+    }
+    Err(batch
+        .iter()
+        .map(|parsed| match parsed {
+            Ok(row) => row.rejection(&TxError::BatchAborted {
+                client: row.command.client,
+                tx: row.command.tx,
+            }),
+            Err(malformed) => malformed.rejection(),
+        })
+        .collect())
+}
+
+/// Read rows of input in given `format`, or in format detected from the input
+pub(crate) fn rows<'a, R: std::io::Read + 'a>(
+    raw_reader: R,
+    format: Option<InputFormat>,
+) -> Result<Box<dyn Iterator<Item = Result<Parsed>> + 'a>> {
+    let mut reader = BufReader::new(raw_reader);
+    let format = match format {
+        Some(format) => format,
+        None => InputFormat::detect(&mut reader)?,
+    };
+    Ok(match format {
+        InputFormat::Csv => Box::new(csv_rows(reader)?),
+        InputFormat::Ndjson => Box::new(ndjson_rows(reader)),
+    })
+}
+
+/// Rows grouped into batches: consecutive rows with the same batch ID form one batch, every
+/// other row is a batch of its own
+pub(crate) fn batches<I: Iterator<Item = Result<Parsed>>>(
+    rows: I,
+) -> impl Iterator<Item = Result<Vec<Parsed>>> {
+    let mut rows = rows.peekable();
+    std::iter::from_fn(move || {
+        let first = match rows.next()? {
+            Ok(parsed) => parsed,
+            Err(error) => return Some(Err(error)),
+        };
+        let id = batch_of(&first).cloned();
+        let mut batch = vec![first];
+        if let Some(id) = id {
+            // Failure to read ends the input, so it's reported instead of a partial batch
+            let same_batch = |row: &Result<Parsed>| {
+                row.as_ref()
+                    .map_or(true, |parsed| batch_of(parsed) == Some(&id))
+            };
+            while let Some(row) = rows.next_if(same_batch) {
+                match row {
+                    Ok(parsed) => batch.push(parsed),
+                    Err(error) => return Some(Err(error)),
+                }
+            }
+        }
+        Some(Ok(batch))
+    })
+}
+
+/// Column names of CSV input, for rows coming without header; columns after the amount are
+/// optional
+pub(crate) const HEADER: [&str; 13] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "currency",
+    "to_currency",
+    "time",
+    "to_client",
+    "batch",
+    "operator",
+    "reason",
+    "restriction",
+    "restriction_reason",
+];
+
+/// Whether `line` is the header of CSV input rather than a row, i.e. starts with a column name
+pub(crate) fn is_csv_header(line: &str) -> bool {
+    let first = line.split(',').next().unwrap_or_default().trim();
+    HEADER.contains(&first)
+}
+
+/// Header used for rows without one
+pub(crate) fn default_header() -> csv::StringRecord {
+    csv::StringRecord::from(&HEADER[..])
+}
+
+/// Parse a single CSV line into fields
+pub(crate) fn csv_record(line: &str) -> Result<csv::StringRecord> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .from_reader(line.as_bytes());
+    Ok(reader
+        .records()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("empty row"))??)
+}
+
+/// Parse a single CSV row with columns named by `header`
+pub(crate) fn csv_row(line: &str, header: &csv::StringRecord) -> Result<io::Command> {
+    Ok(csv_record(line)?.deserialize(Some(header))?)
+}
+
+/// Reader that keeps what was read, so that rows can be reported exactly as they were
+struct Recorder<R> {
+    inner: R,
+    /// Bytes read since `start`
+    recorded: Rc<RefCell<Vec<u8>>>,
+}
+
+impl<R: std::io::Read> std::io::Read for Recorder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.recorded.borrow_mut().extend_from_slice(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Read rows of CSV input with header
+pub(crate) fn csv_rows<'a, R: std::io::Read + 'a>(
+    raw_reader: R,
+) -> Result<impl Iterator<Item = Result<Parsed>> + 'a> {
+    let recorded = Rc::new(RefCell::new(Vec::new()));
+    // Rows are trimmed by hand so that the rejection report can show them as they were
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(true)
+        .trim(csv::Trim::None)
+        .flexible(true)
+        .from_reader(Recorder {
+            inner: raw_reader,
+            recorded: recorded.clone(),
+        });
+    let mut headers = reader.headers()?.clone();
+    headers.trim();
+    let batch_column = headers.iter().position(|header| header == "batch");
+    // Input offset of the first recorded byte
+    let mut start = 0;
+    let mut raw_record = csv::StringRecord::new();
+
+    Ok(std::iter::from_fn(move || {
+        let mut record_start = reader.position().clone();
+        let ret = reader.read_record(&mut raw_record);
+        let end = reader.position().byte();
+        let command = match ret {
+            Ok(false) => return None,
+            Ok(true) => {
+                record_start = raw_record.position().unwrap_or(&record_start).clone();
+                let mut record = raw_record.clone();
+                record.trim();
+                record.deserialize(Some(&headers))
+            }
+            Err(error) if error.is_io_error() => return Some(Err(error.into())),
+            Err(error) => {
+                if let Some(position) = error.position() {
+                    record_start = position.clone();
+                }
+                Err(error)
+            }
+        };
+
+        let mut recorded = recorded.borrow_mut();
+        // Bytes before the previous row end are dropped, so what's left is small
+        let row_end = ((end - start) as usize).min(recorded.len());
+        let mut row_start = (record_start.byte().saturating_sub(start) as usize).min(row_end);
+        let (mut line, mut offset) = (record_start.line(), record_start.byte());
+        // Reader counts the rest of a CRLF terminator to the next row
+        while let Some(&byte) = recorded[row_start..row_end].first() {
+            if byte != b'\r' && byte != b'\n' {
+                break;
+            }
+            line += u64::from(byte == b'\n');
+            offset += 1;
+            row_start += 1;
+        }
+        let raw = String::from_utf8_lossy(&recorded[row_start..row_end])
+            .trim_end_matches(&['\n', '\r'][..])
+            .to_owned();
+        recorded.drain(..row_end);
+        start += row_end as u64;
+
+        Some(Ok(match command {
+            Ok(command) => Ok(Row {
+                line,
+                offset,
+                raw,
+                command,
+            }),
+            Err(error) => Err(Malformed {
+                line,
+                offset,
+                raw,
+                batch: batch_column
+                    .and_then(|column| raw_record.get(column))
+                    .map(str::trim)
+                    .filter(|batch| !batch.is_empty())
+                    .map(str::to_owned),
+                message: error.to_string(),
+            }),
+        }))
+    }))
+}
+
+/// Read rows of NDJSON input, blank lines are skipped
+fn ndjson_rows<R: BufRead>(mut reader: R) -> impl Iterator<Item = Result<Parsed>> {
+    let mut line = 0;
+    let mut offset = 0;
+    let mut buffer = String::new();
+    std::iter::from_fn(move || loop {
+        buffer.clear();
+        let len = match reader.read_line(&mut buffer) {
+            Ok(0) => return None,
+            Ok(len) => len,
+            Err(error) => return Some(Err(error.into())),
+        };
+        line += 1;
+        let row_offset = offset;
+        offset += len as u64;
+        let raw = buffer.trim_end_matches(&['\n', '\r'][..]);
+        if raw.trim().is_empty() {
+            continue;
+        }
+        return Some(Ok(match serde_json::from_str(raw) {
+            Ok(command) => Ok(Row {
+                line,
+                offset: row_offset,
+                raw: raw.to_owned(),
+                command,
+            }),
+            Err(error) => Err(Malformed {
+                line,
+                offset: row_offset,
+                raw: raw.to_owned(),
+                batch: serde_json::from_str::<serde_json::Value>(raw)
+                    .ok()
+                    .and_then(|value| value.get("batch")?.as_str().map(str::to_owned)),
+                message: error.to_string(),
+            }),
+        }));
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn commands(input: &str, format: Option<InputFormat>) -> Vec<(u64, u64, io::Command)> {
+        rows(input.as_bytes(), format)
+            .unwrap()
+            .map(|row| {
+                let row = row.unwrap().unwrap();
+                (row.line, row.offset, row.command)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn formats() {
+        let deposit = io::Command::new(io::CommandType::Deposit, 1, 2).amount("1.5");
+        let dispute = io::Command::new(io::CommandType::Dispute, 1, 2);
+
+        let csv = "type, client, tx, amount\ndeposit, 1, 2, 1.5\ndispute, 1, 2,\n";
+        let expected = vec![(2, 25, deposit.clone()), (3, 44, dispute.clone())];
+        assert_eq!(commands(csv, None), expected);
+        assert_eq!(commands(csv, Some(InputFormat::Csv)), expected);
+
+        let ndjson = "{\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":\"1.5\"}\n\
+                      \n\
+                      {\"type\":\"dispute\",\"client\":1,\"tx\":2}\n";
+        let expected = vec![(1, 0, deposit.clone()), (3, 53, dispute)];
+        assert_eq!(commands(ndjson, None), expected);
+        assert_eq!(commands(ndjson, Some(InputFormat::Ndjson)), expected);
+
+        // Currency column is optional, even within one input
+        let usd = deposit.clone().currency("USD".parse().unwrap());
+        let csv = "type,client,tx,amount,currency
+deposit,1,2,1.5,USD
+deposit,1,2,1.5
+";
+        assert_eq!(
+            commands(csv, None),
+            vec![(2, 31, usd.clone()), (3, 51, deposit.clone())]
+        );
+        let ndjson = "{\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":\"1.5\",\"currency\":\"USD\"}\n";
+        assert_eq!(commands(ndjson, None), vec![(1, 0, usd)]);
+        assert_eq!(
+            csv_row("deposit, 1, 2, 1.5", &default_header()).unwrap(),
+            deposit
+        );
+
+        // Amounts have to be strings to stay exact
+        let mut rows = rows(
+            &b"{\"type\":\"deposit\",\"client\":1,\"tx\":2,\"amount\":1.5}"[..],
+            None,
+        )
+        .unwrap();
+        assert!(rows.next().unwrap().unwrap().is_err());
+    }
+
+    #[test]
+    fn malformed() {
+        let csv = "type,client,tx,amount,currency,to_currency,time,to_client,batch
+deposit,1,2,\"1,5\"\r
+depost,1,3,5
+deposit,1,4,1.5,,,,,a
+deposit,x,5,1.5,,,,,a
+deposit,1,6,1.5
+";
+        let rows = rows(csv.as_bytes(), None)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let row = rows[0].as_ref().unwrap();
+        assert_eq!(row.raw, "deposit,1,2,\"1,5\"");
+        assert_eq!(row.command.amount.as_deref(), Some("1,5"));
+        let malformed = rows[1].as_ref().unwrap_err();
+        assert_eq!((malformed.line, malformed.offset), (3, 83));
+        assert_eq!(malformed.raw, "depost,1,3,5");
+        assert_eq!(malformed.batch, None);
+        assert_eq!(rows[3].as_ref().unwrap_err().batch.as_deref(), Some("a"));
+        assert_eq!(rows[4].as_ref().unwrap().raw, "deposit,1,6,1.5");
+
+        // Malformed row ends up in a batch of its own, or rejects the batch it belongs to
+        let batches = batches(rows.into_iter().map(Ok))
+            .map(|batch| parsed(batch.unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(batches.len(), 4);
+        let codes = |batch: &std::result::Result<Vec<Row>, Vec<io::Rejection>>| {
+            batch
+                .as_ref()
+                .unwrap_err()
+                .iter()
+                .map(|rejection| (rejection.code.clone(), rejection.tx))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(codes(&batches[1]), vec![(MALFORMED.to_owned(), None)]);
+        assert_eq!(
+            codes(&batches[2]),
+            vec![
+                ("batch_aborted".to_owned(), Some(4)),
+                (MALFORMED.to_owned(), None)
+            ]
+        );
+        assert_eq!(batches[3].as_ref().unwrap()[0].command.tx, 6);
+
+        let ndjson =
+            "{\"type\":\"deposit\",\"client\":1,\"tx\":2,\"batch\":\"a\"}\n{\"type\":\"depost\"}\n";
+        let rows = super::rows(ndjson.as_bytes(), None)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        let malformed = rows[1].as_ref().unwrap_err();
+        assert_eq!(
+            (malformed.line, malformed.raw.as_str()),
+            (2, "{\"type\":\"depost\"}")
+        );
+    }
+}
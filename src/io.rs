@@ -1,11 +1,14 @@
 //! Types for de/serializing input and output
 
-use crate::account;
-use crate::moneys::Moneys;
+use crate::account::{self, AccountStatus};
+use crate::currency::Currency;
+use crate::error::TxError;
+use crate::moneys::{Moneys, Rounding};
 use crate::processor;
-use anyhow::{bail, Result};
-use serde::{Deserialize, Serialize, Serializer};
-use std::convert::TryFrom;
+use crate::restriction::{Restriction, RestrictionReason};
+use serde::de::IntoDeserializer as _;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
 
 #[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
 #[serde(rename_all = "snake_case")]
@@ -15,44 +18,195 @@ pub enum CommandType {
     Dispute,
     Resolve,
     Chargeback,
+    /// Move amount from `currency` to `to_currency` of the same account
+    Convert,
+    /// Move amount from account of `client` to account of `to_client`
+    Transfer,
+    /// Lift the lock caused by a chargeback
+    Unlock,
+    /// Restrict the account with `restriction` (frozen by default) for `restriction_reason`
+    /// (manual by default)
+    Freeze,
+    /// Lift the restriction set for `restriction_reason` (manual by default)
+    Unfreeze,
+    /// Put the account permanently out of use; it has to be empty, unless its available money
+    /// is swept to `to_client`
+    Close,
+}
+
+impl FromStr for CommandType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        Self::deserialize(s.into_deserializer())
+            .map_err(|_: serde::de::value::Error| format!("unknown command type {:?}", s))
+    }
+}
+
+impl CommandType {
+    /// Whether the command is an operator action on the account rather than a transaction
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            self,
+            CommandType::Unlock | CommandType::Freeze | CommandType::Unfreeze | CommandType::Close
+        )
+    }
 }
 
 /// I probably wouldn't use the same struct for both passing around and for serialization, but
 /// I have no time now
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Command {
     #[serde(rename = "type")]
     pub command_type: CommandType,
+    /// Client whose account the command applies to, the payer of a transfer
+    #[serde(alias = "from_client")]
     pub client: account::ClientId,
     pub tx: processor::TransactionId,
-    pub amount: Option<f64>,
+    /// Kept as the original decimal string, so that it can be parsed exactly
+    pub amount: Option<String>,
+    /// Currency of the amount; disputes may leave it out to use the one of the transaction
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    /// Currency a conversion is made to
+    #[serde(default)]
+    pub to_currency: Option<Currency>,
+    /// Unix time of a conversion, picks the exchange rate
+    #[serde(default)]
+    pub time: Option<u64>,
+    /// Recipient of a transfer, or of money swept from a closed account
+    #[serde(default)]
+    pub to_client: Option<account::ClientId>,
+    /// Identifier of the batch; consecutive commands of the same batch are executed all or none
+    #[serde(default)]
+    pub batch: Option<String>,
+    /// Who issued an admin command, recorded with it
+    #[serde(default)]
+    pub operator: Option<String>,
+    /// Why an admin command was issued, recorded with it
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// Restriction set by `freeze`
+    #[serde(default)]
+    pub restriction: Option<Restriction>,
+    /// Reason of restriction set or lifted by `freeze` or `unfreeze`
+    #[serde(default)]
+    pub restriction_reason: Option<RestrictionReason>,
 }
 
 impl Command {
-    pub fn get_moneys(&self) -> Result<Moneys> {
-        match self.amount {
-            None => bail!("transaction is missing amount of money"),
-            Some(amount) => Moneys::try_from(amount),
+    /// Command without amount or any optional column; set them with the methods below
+    pub fn new(
+        command_type: CommandType,
+        client: account::ClientId,
+        tx: processor::TransactionId,
+    ) -> Self {
+        Self {
+            command_type,
+            client,
+            tx,
+            amount: None,
+            currency: None,
+            to_currency: None,
+            time: None,
+            to_client: None,
+            batch: None,
+            operator: None,
+            reason: None,
+            restriction: None,
+            restriction_reason: None,
         }
     }
-}
 
-fn four_decimal_places<S>(x: &f64, s: S) -> Result<S::Ok, S::Error>
-where
-    S: Serializer,
-{
-    s.serialize_str(&format!("{:.04}", x))
-}
+    pub fn amount(mut self, amount: impl Into<String>) -> Self {
+        self.amount = Some(amount.into());
+        self
+    }
+
+    pub fn currency(mut self, currency: Currency) -> Self {
+        self.currency = Some(currency);
+        self
+    }
+
+    pub fn to_currency(mut self, to_currency: Currency) -> Self {
+        self.to_currency = Some(to_currency);
+        self
+    }
+
+    pub fn time(mut self, time: u64) -> Self {
+        self.time = Some(time);
+        self
+    }
 
+    pub fn to_client(mut self, to_client: account::ClientId) -> Self {
+        self.to_client = Some(to_client);
+        self
+    }
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+    pub fn batch(mut self, batch: impl Into<String>) -> Self {
+        self.batch = Some(batch.into());
+        self
+    }
+
+    pub fn operator(mut self, operator: impl Into<String>) -> Self {
+        self.operator = Some(operator.into());
+        self
+    }
+
+    pub fn reason(mut self, reason: impl Into<String>) -> Self {
+        self.reason = Some(reason.into());
+        self
+    }
+
+    pub fn restriction(mut self, restriction: Restriction) -> Self {
+        self.restriction = Some(restriction);
+        self
+    }
+
+    pub fn restriction_reason(mut self, reason: RestrictionReason) -> Self {
+        self.restriction_reason = Some(reason);
+        self
+    }
+
+    /// Parse amount with `scale` decimal places
+    pub fn get_moneys(&self, scale: u8, rounding: Rounding) -> Result<Moneys, TxError> {
+        let (client, tx) = (self.client, self.tx);
+        match &self.amount {
+            None => Err(TxError::MissingAmount { client, tx }),
+            Some(amount) => Moneys::parse(amount, scale, rounding)
+                .map_err(|source| TxError::InvalidAmount { client, tx, source }),
+        }
+    }
+}
+
+/// Balance of an account in one currency
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Account {
     pub client: account::ClientId,
-    #[serde(serialize_with = "four_decimal_places")]
-    pub available: f64,
-    #[serde(serialize_with = "four_decimal_places")]
-    pub held: f64,
-    #[serde(serialize_with = "four_decimal_places")]
-    pub total: f64,
+    /// Left out for the default currency
+    #[serde(default, skip_serializing_if = "Currency::is_default")]
+    pub currency: Currency,
+    pub available: Moneys,
+    pub held: Moneys,
+    pub total: Moneys,
     pub locked: bool,
+    /// Left out for open accounts
+    #[serde(default, skip_serializing_if = "AccountStatus::is_open")]
+    pub status: AccountStatus,
+}
+
+/// Record of a command that was refused by the processor
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Rejection {
+    /// Line of the input the command was read from
+    pub line: u64,
+    /// The input row as it was read
+    pub row: String,
+    /// Machine-readable reason (see `TxError::code`)
+    pub code: String,
+    /// Left out for rows that couldn't be parsed
+    pub client: Option<account::ClientId>,
+    pub tx: Option<processor::TransactionId>,
+    /// Human-readable reason
+    pub message: String,
 }
@@ -0,0 +1,415 @@
+//! Write-ahead journal of accepted commands
+//!
+//! Journal is a sequence of JSON records, one per line. The first record is a header with
+//! format version, every other record holds the state of account and transaction after an
+//! accepted command, together with offset of the command in the input, or such entries of all
+//! commands of a batch. Records are written before the processor state is changed, so replaying
+//! the journal rebuilds the exact state even after a crash, and a batch is either replayed
+//! whole or not at all. Rejected rows of an input are recorded too, so that a resumed run can
+//! report them again without evaluating them twice.
+//!
+//! Offsets only make sense in the input they were read from, so a journal of an input file
+//! keeps a hash of the beginning of the file in its header and can't be resumed with another.
+
+use crate::account::Account;
+use crate::io::Rejection;
+use crate::processor::{Transaction, TransactionId};
+use anyhow::{bail, Context as _, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Version written into new journals. Entries hold accounts and transactions, so it changes
+/// together with their format; other versions are refused.
+const VERSION: u32 = 2;
+
+/// State of accounts and transaction after an accepted command
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Entry {
+    /// Offset of the command in the input, if known
+    pub offset: Option<u64>,
+    pub account: Account,
+    /// Account of the other client of a transfer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub counterparty: Option<Account>,
+    pub tx: TransactionId,
+    pub transaction: Transaction,
+}
+
+/// Row of the input refused by the processor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Rejected {
+    /// Offset of the row in the input
+    pub offset: u64,
+    pub rejection: Rejection,
+}
+
+/// What `Journal::for_each` finds in the journal, only lives while it's replayed
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub(crate) enum Replayed {
+    Entry(Entry),
+    Rejected(Rejected),
+}
+
+// Records only live while being written or read
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Record {
+    Header {
+        version: u32,
+        /// Beginning of the input the offsets of entries belong to
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        input: Option<InputPrefix>,
+    },
+    Entry(Entry),
+    /// Entries of all commands of a batch, in order
+    Batch(Vec<Entry>),
+    /// Rejected rows of a single command or of a whole batch, in order
+    Rejected(Vec<Rejected>),
+}
+
+/// Length and FNV-1a hash of the beginning of an input file. It stays the same when more rows
+/// are appended to the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct InputPrefix {
+    len: u64,
+    hash: u64,
+}
+
+impl InputPrefix {
+    /// How much of the input is hashed for new journals
+    const MAX_LEN: u64 = 64 * 1024;
+
+    /// Hash up to `len` bytes from the current position of `input` and seek back
+    fn read<R: Read + Seek>(input: &mut R, len: u64) -> io::Result<Self> {
+        let start = input.stream_position()?;
+        let mut prefix = Self {
+            len: 0,
+            hash: 0xcbf2_9ce4_8422_2325,
+        };
+        let mut buffer = [0; 4096];
+        let mut reader = input.by_ref().take(len);
+        loop {
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            };
+            for &byte in &buffer[..read] {
+                prefix.hash = (prefix.hash ^ u64::from(byte)).wrapping_mul(0x100_0000_01b3);
+            }
+            prefix.len += read as u64;
+        }
+        input.seek(SeekFrom::Start(start))?;
+        Ok(prefix)
+    }
+}
+
+/// Append-only journal file
+#[derive(Debug)]
+pub struct Journal {
+    file: fs::File,
+    sync: bool,
+}
+
+impl Journal {
+    /// Open journal at `path`, creating it if it doesn't exist yet. Record cut short by a crash
+    /// is dropped.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::open_for::<fs::File>(path.as_ref(), None)
+    }
+
+    /// Open journal at `path` for processing `input` from its current position, creating it if
+    /// it doesn't exist yet. Existing journal has to be written for the same input, otherwise
+    /// it's refused.
+    pub fn open_with_input<P: AsRef<Path>, R: Read + Seek>(
+        path: P,
+        input: &mut R,
+    ) -> io::Result<Self> {
+        Self::open_for(path.as_ref(), Some(input))
+    }
+
+    fn open_for<R: Read + Seek>(path: &Path, input: Option<&mut R>) -> io::Result<Self> {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let len = Self::complete_len(&mut file)?;
+        file.set_len(len)?;
+        file.seek(SeekFrom::End(0))?;
+
+        let mut journal = Self { file, sync: true };
+        if len == 0 {
+            let input = match input {
+                Some(input) => Some(InputPrefix::read(input, InputPrefix::MAX_LEN)?),
+                None => None,
+            };
+            journal.append(&Record::Header {
+                version: VERSION,
+                input,
+            })?;
+        } else if let Some(input) = input {
+            journal.check_input(input)?;
+        }
+        Ok(journal)
+    }
+
+    /// Check that the journal was written for `input`
+    fn check_input<R: Read + Seek>(&mut self, input: &mut R) -> io::Result<()> {
+        let mut header = String::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        io::BufReader::new(&self.file).read_line(&mut header)?;
+        self.file.seek(SeekFrom::End(0))?;
+        let expected = match serde_json::from_str(&header)? {
+            Record::Header {
+                input: Some(expected),
+                ..
+            } => expected,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "journal wasn't written for an input file",
+                ))
+            }
+        };
+        if InputPrefix::read(input, expected.len)? != expected {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "journal was written for a different input",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Don't wait for every record to reach the disk. Faster, but records written shortly
+    /// before a crash of the machine may be lost.
+    pub fn without_sync(mut self) -> Self {
+        self.sync = false;
+        self
+    }
+
+    /// Length of the file up to the last complete record
+    fn complete_len(file: &mut fs::File) -> io::Result<u64> {
+        const CHUNK: u64 = 4096;
+        let mut end = file.metadata()?.len();
+        let mut buffer = [0; CHUNK as usize];
+        while end > 0 {
+            let start = end.saturating_sub(CHUNK);
+            let chunk = &mut buffer[..(end - start) as usize];
+            file.seek(SeekFrom::Start(start))?;
+            file.read_exact(chunk)?;
+            if let Some(i) = chunk.iter().rposition(|&byte| byte == b'\n') {
+                return Ok(start + i as u64 + 1);
+            }
+            end = start;
+        }
+        Ok(0)
+    }
+
+    pub(crate) fn append(&mut self, record: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        if self.sync {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Call `f` for every entry and rejected row in the journal, including those of batches
+    pub(crate) fn for_each(&mut self, mut f: impl FnMut(Replayed) -> Result<()>) -> Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        let reader = io::BufReader::new(&self.file);
+        for (i, line) in reader.lines().enumerate() {
+            let record = serde_json::from_str(&line?)
+                .with_context(|| format!("invalid journal record on line {}", i + 1))?;
+            match (i, record) {
+                (
+                    0,
+                    Record::Header {
+                        version: VERSION, ..
+                    },
+                ) => (),
+                (0, Record::Header { version, .. }) => {
+                    bail!("unsupported journal version {}", version)
+                }
+                (0, _) => bail!("journal doesn't start with a header"),
+                (_, Record::Header { .. }) => bail!("duplicate journal header"),
+                (_, Record::Entry(entry)) => f(Replayed::Entry(entry))?,
+                (_, Record::Batch(entries)) => {
+                    for entry in entries {
+                        f(Replayed::Entry(entry))?;
+                    }
+                }
+                (_, Record::Rejected(rejected)) => {
+                    for rejected in rejected {
+                        f(Replayed::Rejected(rejected))?;
+                    }
+                }
+            }
+        }
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::io::{Command, CommandType};
+    use crate::{run_processor, Processor, RejectSink};
+
+    fn deposit(client: u16, tx: TransactionId, amount: &str) -> Command {
+        Command::new(CommandType::Deposit, client, tx).amount(amount)
+    }
+
+    #[test]
+    fn recovery() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+
+        let mut processor = Processor::builder()
+            .journal(Journal::open(&path).unwrap().without_sync())
+            .build();
+        assert_eq!(processor.replay_journal(None).unwrap(), None);
+        processor
+            .execute_at(&deposit(1, 1, "1.0"), Some(10))
+            .unwrap();
+        processor
+            .execute_at(&deposit(1, 2, "2.0"), Some(20))
+            .unwrap();
+        assert!(processor
+            .execute_at(&deposit(2, 2, "2.0"), Some(30))
+            .is_err());
+        // Transfer changes two accounts
+        processor
+            .execute_at(
+                &Command::new(CommandType::Transfer, 1, 4)
+                    .amount("0.5")
+                    .to_client(4),
+                Some(35),
+            )
+            .unwrap();
+        // Batch is journaled as one record
+        processor
+            .execute_batch(&[deposit(5, 5, "1.0"), deposit(6, 6, "1.0")])
+            .unwrap();
+        let expected = processor.accounts();
+        assert_eq!(expected.len(), 4);
+        drop(processor);
+
+        // Crash in the middle of writing a record
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"entry\":{\"offs").unwrap();
+
+        let mut processor = Processor::builder()
+            .journal(Journal::open(&path).unwrap())
+            .build();
+        assert_eq!(processor.replay_journal(None).unwrap(), Some(35));
+        assert_eq!(processor.accounts(), expected);
+        assert!(processor.execute(&deposit(3, 2, "2.0")).is_err());
+
+        // Journal continues after the recovered records
+        processor
+            .execute_at(&deposit(3, 3, "3.0"), Some(40))
+            .unwrap();
+        let expected = processor.accounts();
+        let mut processor = Processor::builder()
+            .journal(Journal::open(&path).unwrap())
+            .build();
+        assert_eq!(processor.replay_journal(None).unwrap(), Some(40));
+        assert_eq!(processor.accounts(), expected);
+    }
+
+    #[test]
+    fn rejections() {
+        #[derive(Default)]
+        struct Rejections(Vec<Rejection>);
+
+        impl RejectSink for Rejections {
+            fn reject(&mut self, rejection: &Rejection) -> Result<()> {
+                self.0.push(rejection.clone());
+                Ok(())
+            }
+
+            fn finish(&mut self) -> Result<()> {
+                Ok(())
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     withdrawal,1,2,5.0\n\
+                     deposit,x\n";
+        let open = || {
+            Processor::builder()
+                .journal(Journal::open(&path).unwrap().without_sync())
+                .build()
+        };
+
+        let mut processor = open();
+        let mut rejects = Rejections::default();
+        run_processor(
+            &mut processor,
+            input.as_bytes(),
+            None,
+            false,
+            Some(&mut rejects),
+            None,
+        )
+        .unwrap();
+        assert_eq!(rejects.0.len(), 2);
+        drop(processor);
+
+        // Resumed run reports the rejections again without evaluating the rows twice
+        let mut processor = open();
+        let mut resumed = Rejections::default();
+        let resume_after = processor.replay_journal(Some(&mut resumed)).unwrap();
+        assert_eq!(resumed.0, rejects.0);
+        run_processor(
+            &mut processor,
+            input.as_bytes(),
+            None,
+            false,
+            Some(&mut resumed),
+            resume_after,
+        )
+        .unwrap();
+        assert_eq!(resumed.0, rejects.0);
+    }
+
+    #[test]
+    fn input() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let input = b"type,client,tx,amount\ndeposit,1,1,1.0\n";
+
+        let mut reader = io::Cursor::new(&input[..]);
+        Journal::open_with_input(&path, &mut reader).unwrap();
+        assert_eq!(reader.position(), 0);
+        // More rows can be appended to the input
+        let mut longer = input.to_vec();
+        longer.extend_from_slice(b"deposit,1,2,1.0\n");
+        Journal::open_with_input(&path, &mut io::Cursor::new(longer)).unwrap();
+
+        let mut other = input.to_vec();
+        other[30] = b'2';
+        let error = Journal::open_with_input(&path, &mut io::Cursor::new(other)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(Journal::open_with_input(&path, &mut io::Cursor::new(&input[..10])).is_err());
+
+        // Journal of a server has no input
+        let path = dir.path().join("server-journal");
+        Journal::open(&path).unwrap();
+        assert!(Journal::open(&path).is_ok());
+        assert!(Journal::open_with_input(&path, &mut io::Cursor::new(&input[..])).is_err());
+    }
+}
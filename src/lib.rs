@@ -1,50 +1,116 @@
-pub use account::Account;
+pub use account::{Account, AccountStatus, Balance};
 use anyhow::Result;
-pub use processor::Processor;
+pub use currency::{Currency, CurrencyError};
+pub use error::{BatchError, TxError};
+pub use input::InputFormat;
+pub use journal::Journal;
+pub use moneys::{Moneys, MoneysError, Rounding};
+pub use output::{print_accounts, print_rows, AccountSelection, OutputFormat, SortKey};
+pub use parallel::run_parallel;
+pub use policy::{DisputeShortfall, LenientPolicy, Policy, StandardPolicy, StrictPolicy};
+pub use processor::{
+    AdminAction, DisputedAmounts, Processor, ProcessorBuilder, Transaction, TransactionId,
+};
+pub use rates::{Rate, RateTable};
+pub use rejects::{RejectFormat, RejectSink, RejectWriter};
+pub use restriction::{Restriction, RestrictionPolicy, RestrictionReason};
+pub use server::serve;
+pub use socket::serve_tcp;
+#[cfg(unix)]
+pub use socket::serve_unix;
+use std::collections::HashMap;
+pub use store::{FileStore, MemoryStore, TransactionStore};
+pub use stream::Outcome;
 
 mod account;
+mod currency;
+mod error;
+mod input;
 pub mod io;
+mod journal;
 mod moneys;
+mod output;
+mod parallel;
+mod policy;
 mod processor;
+mod rates;
+mod rejects;
+mod restriction;
+mod server;
+mod snapshot;
+mod socket;
+mod store;
+mod stream;
 pub use account::ClientId;
 
-/// Stream input CSV file through transaction processor. Optionally print debug info (like errors 
-/// and parsed data).
+/// Stream input file in given `format` (detected when `None`) through transaction processor.
+/// Optionally print debug info (like errors and parsed data) and report rejected commands to
+/// `rejects`, they are recorded in the journal too. Rows starting at or before byte offset
+/// `resume_after` are skipped (see `Processor::replay_journal`).
 pub fn run_processor<R: std::io::Read>(
     processor: &mut Processor,
     raw_reader: R,
+    format: Option<InputFormat>,
     verbose: bool,
+    mut rejects: Option<&mut dyn RejectSink>,
+    resume_after: Option<u64>,
 ) -> Result<()> {
-    let mut reader = csv::ReaderBuilder::new()
-        .has_headers(true)
-        .trim(csv::Trim::All)
-        .flexible(true)
-        .from_reader(raw_reader);
-
     // Run transactions through processor
-    for result in reader.deserialize::<io::Command>() {
-        let command = result?;
-        if verbose {
-            println!("command: {:?}", command);
+    for batch in input::batches(input::rows(raw_reader, format)?) {
+        let mut batch = batch?;
+        // Batch is journaled whole, so it is either skipped whole or not at all
+        batch.retain(|row| {
+            resume_after.is_none_or(|resume_after| input::offset_of(row) > resume_after)
+        });
+        if batch.is_empty() {
+            continue;
         }
-        let ret = processor.execute(&command);
+        let offsets: Vec<_> = batch.iter().map(input::offset_of).collect();
+        let batch = match input::parsed(batch) {
+            Ok(batch) => batch,
+            Err(rejections) => {
+                if verbose {
+                    for rejection in &rejections {
+                        println!("rejected: {:?}", rejection);
+                    }
+                }
+                let rejections: Vec<_> = offsets.into_iter().zip(rejections).collect();
+                report(processor, &rejections, &mut rejects)?;
+                continue;
+            }
+        };
         if verbose {
-            println!("result: {:?}", ret);
+            for row in &batch {
+                println!("command: {:?}", row.command);
+            }
+        }
+        let results = processor.execute_rows(&batch, &mut HashMap::new());
+        let mut rejections = vec![];
+        for (row, ret) in batch.iter().zip(results) {
+            if verbose {
+                println!("result: {:?}", ret);
+            }
+            if let Err(error) = ret {
+                rejections.push((row.offset, row.rejection(&error)));
+            }
         }
+        report(processor, &rejections, &mut rejects)?;
     }
 
     Ok(())
 }
 
-/// Serialize accounts as CSV
-pub fn print_accounts<W: std::io::Write>(
-    raw_writer: W,
-    accounts: &[account::Account],
+/// Record rows rejected together in the journal and report them to `rejects`
+fn report(
+    processor: &mut Processor,
+    rejections: &[(u64, io::Rejection)],
+    rejects: &mut Option<&mut dyn RejectSink>,
 ) -> Result<()> {
-    let mut writer = csv::WriterBuilder::new().from_writer(raw_writer);
-    for account in accounts {
-        writer.serialize(io::Account::from(account.clone()))?;
+    processor.journal_rejections(rejections)?;
+    if let Some(rejects) = rejects {
+        for (_, rejection) in rejections {
+            rejects.reject(rejection)?;
+        }
     }
-    writer.flush()?;
     Ok(())
 }
@@ -1,36 +1,392 @@
-use anyhow::{Context as _, Result};
-use clap::Parser;
+use anyhow::{bail, Context as _, Result};
+#[cfg(unix)]
+use clap::ArgGroup;
+use clap::{Parser, Subcommand};
 use std::fs;
 use std::io;
 use std::path;
-use transaction_processor::{print_accounts, run_processor, Processor};
+use transaction_processor::io::CommandType;
+#[cfg(unix)]
+use transaction_processor::serve_unix;
+use transaction_processor::{
+    print_rows, run_parallel, run_processor, serve, serve_tcp, Account, AccountSelection, ClientId,
+    Currency, FileStore, InputFormat, Journal, LenientPolicy, Moneys, OutputFormat, Policy,
+    Processor, ProcessorBuilder, RateTable, RejectFormat, RejectSink, RejectWriter, Restriction,
+    RestrictionPolicy, Rounding, SortKey, StandardPolicy, StrictPolicy,
+};
 
 /// Definition of command-line arguments
 #[derive(Debug, Parser)]
+#[clap(subcommand_negates_reqs = true)]
 struct Cli {
     /// Whether to output some more information
     #[clap(short, long)]
     verbose: bool,
+    /// How to treat amounts with more decimal places than the scale (reject, truncate,
+    /// half-even)
+    #[clap(long, default_value = "half-even")]
+    rounding: Rounding,
+    /// Number of decimal places of amounts, like 0 for JPY, 2 for USD or 8 for BTC
+    #[clap(long, default_value = "4")]
+    scale: u8,
+    /// Scale of a currency that differs from the default one, like USD=2; may be repeated or
+    /// separated by commas
+    #[clap(long, parse(try_from_str = parse_currency_scale), use_value_delimiter = true)]
+    currency_scale: Vec<(Currency, u8)>,
+    /// Exchange rates for conversions, CSV with columns pair (like EUR/USD), rate and
+    /// valid_from (Unix time)
+    #[clap(long, parse(from_os_str))]
+    rates: Option<path::PathBuf>,
+    /// How to treat converted amounts with more decimal places than the scale
+    #[clap(long, default_value = "half-even")]
+    conversion_rounding: Rounding,
+    /// Refuse commands that would raise account balance above this amount
+    #[clap(long)]
+    max_balance: Option<Moneys>,
+    /// Business rules for disputes and restricted accounts (standard, strict, lenient)
+    #[clap(long, default_value = "standard")]
+    policy: PolicyName,
+    /// Restriction set on chargeback (frozen, deposits_only, withdrawals_blocked)
+    #[clap(long, default_value = "frozen")]
+    chargeback_restriction: Restriction,
+    /// Command types a restriction allows instead of the ones of the policy, like
+    /// deposits_only=deposit+resolve+chargeback; may be repeated
+    #[clap(long, parse(try_from_str = parse_restriction_allows))]
+    restriction_allows: Vec<(Restriction, Vec<CommandType>)>,
+    /// Format of the input file (csv, ndjson); detected from its content by default
+    #[clap(long)]
+    input_format: Option<InputFormat>,
+    /// Format of the accounts output (csv, json, ndjson, table)
+    #[clap(long, default_value = "csv")]
+    output_format: OutputFormat,
+    /// Order accounts by (client, available, held, total, locked)
+    #[clap(long, default_value = "client")]
+    sort: SortKey,
+    /// Output only locked accounts
+    #[clap(long)]
+    locked_only: bool,
+    /// Output only accounts with some money
+    #[clap(long)]
+    non_zero: bool,
+    /// Output only accounts of these clients (comma-separated)
+    #[clap(long, use_value_delimiter = true)]
+    clients: Option<Vec<ClientId>>,
+    /// Write rejected transactions to this file
+    #[clap(long, parse(from_os_str))]
+    rejects: Option<path::PathBuf>,
+    /// Format of the rejected transactions file (csv, json); guessed from extension by default
+    #[clap(long)]
+    rejects_format: Option<RejectFormat>,
+    /// Keep transactions on disk in this directory instead of memory (in a subdirectory per
+    /// shard with --shards)
+    #[clap(long, parse(from_os_str))]
+    store: Option<path::PathBuf>,
+    /// Continue from state saved by previous run
+    #[clap(long, parse(from_os_str))]
+    state_in: Option<path::PathBuf>,
+    /// Save state after processing, so that next run can continue from it
+    #[clap(long, parse(from_os_str))]
+    state_out: Option<path::PathBuf>,
+    /// Record accepted transactions in this journal; after a crash, run again with the same
+    /// journal to recover and resume where the previous run stopped
+    #[clap(long, parse(from_os_str))]
+    journal: Option<path::PathBuf>,
+    /// Process clients in this many threads (not with state files or the journal, see README)
+    #[clap(
+        long,
+        default_value = "1",
+        conflicts_with_all = &["state-in", "state-out", "journal"]
+    )]
+    shards: usize,
     /// The path to the file to read
-    #[clap(parse(from_os_str))]
-    path: path::PathBuf,
+    #[clap(parse(from_os_str), required = true)]
+    path: Option<path::PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Serve the processor over HTTP instead of processing a file
+    Serve {
+        /// Address to listen on
+        #[clap(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+        /// Number of threads handling requests
+        #[clap(long, default_value = "4")]
+        threads: usize,
+    },
+    /// Accept CSV rows over a TCP or Unix domain socket, answering every row with an ack or
+    /// reject line
+    #[cfg_attr(
+        unix,
+        clap(group(ArgGroup::new("socket").required(true).args(&["tcp", "unix"])))
+    )]
+    Ingest {
+        /// TCP address to listen on
+        #[clap(long)]
+        tcp: Option<String>,
+        /// Path of Unix domain socket to listen on
+        #[cfg(unix)]
+        #[clap(long, parse(from_os_str))]
+        unix: Option<path::PathBuf>,
+    },
 }
 
 fn main() -> Result<()> {
     let args = Cli::parse();
-    let f = fs::File::open(&args.path)
-        .with_context(|| format!("failed opening input file {:?}", args.path))?;
+    let command = match (&args.command, &args.path) {
+        (Some(command), _) => command,
+        (None, Some(path)) => return run_file(&args, path),
+        (None, None) => unreachable!("path is required without subcommand"),
+    };
+    if args.shards > 1 || args.state_out.is_some() || args.rejects.is_some() {
+        bail!("--shards, --state-out and --rejects can't be used with a subcommand");
+    }
+    let (processor, _) = build_processor::<fs::File>(&args, None, None)?;
+    match command {
+        Command::Serve { listen, threads } => serve(processor, listen, *threads, args.verbose),
+        Command::Ingest {
+            tcp: Some(address), ..
+        } => serve_tcp(processor, address, args.verbose),
+        #[cfg(unix)]
+        Command::Ingest {
+            unix: Some(path), ..
+        } => serve_unix(processor, path, args.verbose),
+        #[cfg(unix)]
+        Command::Ingest { .. } => unreachable!("socket is required"),
+        #[cfg(not(unix))]
+        Command::Ingest { .. } => {
+            bail!("--tcp is required, Unix domain sockets aren't supported on this platform")
+        }
+    }
+}
+
+/// Process input file and print the resulting accounts
+fn run_file(args: &Cli, path: &path::Path) -> Result<()> {
+    let f =
+        fs::File::open(path).with_context(|| format!("failed opening input file {:?}", path))?;
     let raw_reader = io::BufReader::new(f);
 
-    // Build processor
-    let mut processor: Processor = Default::default();
-    // Run all input transactions through it (can be called multiple times)
-    run_processor(&mut processor, raw_reader, args.verbose)
-        .context("error in transaction runner")?;
+    // Open rejection report
+    let mut rejects = match &args.rejects {
+        None => None,
+        Some(path) => {
+            let format = args.rejects_format.unwrap_or_else(|| {
+                match path.extension().and_then(|extension| extension.to_str()) {
+                    Some("json") => RejectFormat::Json,
+                    _ => RejectFormat::Csv,
+                }
+            });
+            let f = fs::File::create(path)
+                .with_context(|| format!("failed creating rejects file {:?}", path))?;
+            Some(RejectWriter::new(io::BufWriter::new(f), format))
+        }
+    };
+
+    let accounts = if args.shards > 1 {
+        run_sharded(args, raw_reader, rejects.as_mut())?
+    } else {
+        run_single(args, raw_reader, rejects.as_mut())?
+    };
+    if let Some(rejects) = rejects.as_mut() {
+        rejects.finish().context("failed writing rejects file")?;
+    }
 
-    // Gather accounts and print them
-    let accounts = processor.accounts();
-    print_accounts(io::stdout(), &accounts)?;
+    // Print accounts
+    let selection = AccountSelection {
+        sort: args.sort,
+        locked_only: args.locked_only,
+        non_zero: args.non_zero,
+        clients: args.clients.clone(),
+    };
+    let rows = selection.apply(accounts)?;
+    print_rows(io::stdout(), &rows, args.output_format)?;
 
     Ok(())
 }
+
+/// Parse `CURRENCY=SCALE`
+fn parse_currency_scale(s: &str) -> Result<(Currency, u8)> {
+    let (currency, scale) = s
+        .split_once('=')
+        .context("expected CURRENCY=SCALE, like USD=2")?;
+    let scale = scale
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid scale {:?}", scale))?;
+    Ok((Currency::new(currency)?, scale))
+}
+
+/// Built-in business-rule policies
+#[derive(Debug, Clone, Copy)]
+enum PolicyName {
+    Standard,
+    Strict,
+    Lenient,
+}
+
+impl std::str::FromStr for PolicyName {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "standard" => Ok(PolicyName::Standard),
+            "strict" => Ok(PolicyName::Strict),
+            "lenient" => Ok(PolicyName::Lenient),
+            _ => Err(format!("unknown policy {:?}", s)),
+        }
+    }
+}
+
+/// Parse `RESTRICTION=TYPE+TYPE`, with nothing after `=` to allow no command type
+fn parse_restriction_allows(s: &str) -> Result<(Restriction, Vec<CommandType>)> {
+    let (restriction, command_types) = s
+        .split_once('=')
+        .context("expected RESTRICTION=TYPE+TYPE, like frozen=resolve+chargeback")?;
+    let restriction = restriction.trim().parse().map_err(anyhow::Error::msg)?;
+    let command_types = command_types
+        .split('+')
+        .map(str::trim)
+        .filter(|command_type| !command_type.is_empty())
+        .map(|command_type| command_type.parse().map_err(anyhow::Error::msg))
+        .collect::<Result<_>>()?;
+    Ok((restriction, command_types))
+}
+
+/// Processor configuration shared by all modes
+fn processor_builder(args: &Cli) -> Result<ProcessorBuilder> {
+    let mut builder = Processor::builder()
+        .rounding(args.rounding)
+        .scale(args.scale)
+        .max_balance(args.max_balance.unwrap_or(Moneys::MAX))
+        .conversion_rounding(args.conversion_rounding)
+        .chargeback_restriction(args.chargeback_restriction);
+    let mut restrictions = match args.policy {
+        PolicyName::Strict => RestrictionPolicy::none(),
+        PolicyName::Standard | PolicyName::Lenient => RestrictionPolicy::default(),
+    };
+    for (restriction, command_types) in &args.restriction_allows {
+        restrictions = restrictions.allow(*restriction, command_types);
+    }
+    let policy: Box<dyn Policy> = match args.policy {
+        PolicyName::Standard => Box::new(StandardPolicy::new(restrictions)),
+        PolicyName::Strict => Box::new(StrictPolicy::new(restrictions)),
+        PolicyName::Lenient => Box::new(LenientPolicy::new(restrictions)),
+    };
+    builder = builder.policy(policy);
+    for (currency, scale) in &args.currency_scale {
+        builder = builder.currency_scale(currency.clone(), *scale);
+    }
+    if let Some(path) = &args.rates {
+        builder = builder.rates(RateTable::open(path)?);
+    }
+    // Processing a file again has to give the same results, whenever it runs
+    if args.command.is_none() {
+        builder = builder.latest_rates();
+    }
+    Ok(builder)
+}
+
+fn open_store(path: &path::Path) -> Result<FileStore> {
+    FileStore::open(path).with_context(|| format!("failed opening transaction store {:?}", path))
+}
+
+/// Build processor and recover its state, returns also where to resume reading the `input`.
+/// Rejections recorded in the journal are reported to `rejects` again.
+fn build_processor<R: io::Read + io::Seek>(
+    args: &Cli,
+    input: Option<&mut R>,
+    rejects: Option<&mut dyn RejectSink>,
+) -> Result<(Processor, Option<u64>)> {
+    let mut builder = processor_builder(args)?;
+    if let Some(path) = &args.store {
+        builder = builder.store(Box::new(open_store(path)?));
+    }
+    if let Some(path) = &args.journal {
+        let journal = match input {
+            Some(input) => Journal::open_with_input(path, input),
+            None => Journal::open(path),
+        }
+        .with_context(|| format!("failed opening journal {:?}", path))?;
+        builder = builder.journal(journal);
+    }
+    let mut processor = builder.build();
+    if let Some(path) = &args.state_in {
+        let f = fs::File::open(path)
+            .with_context(|| format!("failed opening state file {:?}", path))?;
+        processor
+            .restore(f)
+            .with_context(|| format!("failed restoring state from {:?}", path))?;
+    }
+    let resume_after = processor
+        .replay_journal(rejects)
+        .context("failed replaying journal")?;
+    Ok((processor, resume_after))
+}
+
+/// Run input through single processor
+fn run_single<R: io::Read + io::Seek, W: io::Write>(
+    args: &Cli,
+    mut raw_reader: R,
+    mut rejects: Option<&mut RejectWriter<W>>,
+) -> Result<Vec<Account>> {
+    let (mut processor, resume_after) = build_processor(
+        args,
+        Some(&mut raw_reader),
+        rejects
+            .as_deref_mut()
+            .map(|rejects| rejects as &mut dyn RejectSink),
+    )?;
+    // Run all input transactions through it (can be called multiple times)
+    run_processor(
+        &mut processor,
+        raw_reader,
+        args.input_format,
+        args.verbose,
+        rejects.map(|rejects| rejects as &mut dyn RejectSink),
+        resume_after,
+    )
+    .context("error in transaction runner")?;
+
+    if let Some(path) = &args.state_out {
+        let f = fs::File::create(path)
+            .with_context(|| format!("failed creating state file {:?}", path))?;
+        processor
+            .snapshot(f)
+            .with_context(|| format!("failed saving state to {:?}", path))?;
+    }
+
+    Ok(processor.accounts())
+}
+
+/// Run input through several processors in parallel, each handling part of clients
+fn run_sharded<R: io::Read, W: io::Write>(
+    args: &Cli,
+    raw_reader: R,
+    rejects: Option<&mut RejectWriter<W>>,
+) -> Result<Vec<Account>> {
+    let processors = (0..args.shards)
+        .map(|shard| {
+            let mut builder = processor_builder(args)?;
+            if let Some(path) = &args.store {
+                let store = open_store(&path.join(format!("shard-{}", shard)))?;
+                builder = builder.store(Box::new(store));
+            }
+            Ok(builder.build())
+        })
+        .collect::<Result<_>>()?;
+    let processors = run_parallel(
+        processors,
+        raw_reader,
+        args.input_format,
+        args.verbose,
+        rejects.map(|rejects| rejects as &mut dyn RejectSink),
+    )
+    .context("error in transaction runner")?;
+
+    Ok(processors
+        .iter()
+        .flat_map(|processor| processor.accounts())
+        .collect())
+}
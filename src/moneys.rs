@@ -1,154 +1,551 @@
 //! Representation of money
 
-use anyhow::{bail, Result};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::cmp::Ordering;
 use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
 
-/// `Moneys` is type that represents given floating money amount as a integer multiple of
-/// tenthousandth of given currency unit.
+/// `Moneys` is type that represents given money amount as an integer multiple of the smallest
+/// unit of its currency, together with the scale (number of decimal places) of the currency.
+/// Scale 4 (ten-thousandths) is the default, but it can be anything from 0 (like JPY) to 18
+/// (like ETH-like tokens) and more.
 ///
-/// The value stored in `Moneys` has always be less-than-or-euqal to `MAX_EXACT_UNITS`.
+/// Values are parsed from and formatted to decimal strings directly, no floating point
+/// arithmetic is involved anywhere. Values of different scales can be compared and added
+/// together, the result has the larger scale, so no digits are ever lost.
 ///
 /// Moneys has finite precision so it has to be able to throw error on overflow.
-#[derive(Debug, Clone, Copy, PartialEq)]
-pub struct Moneys(u64);
+#[derive(Debug, Clone, Copy)]
+pub struct Moneys {
+    units: u128,
+    scale: u8,
+}
+
+/// Errors of money parsing and arithmetic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum MoneysError {
+    #[error("money value overflow")]
+    Overflow,
+    #[error("money value underflow")]
+    Underflow,
+    #[error("negative money value")]
+    Negative,
+    #[error("malformed money value")]
+    Malformed,
+    #[error("money value has more decimal places than its currency allows")]
+    TooPrecise,
+}
+
+type Result<T, E = MoneysError> = std::result::Result<T, E>;
+
+/// What to do with digits past the last decimal place of the scale when parsing `Moneys`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rounding {
+    /// Refuse values that can't be represented exactly
+    Reject,
+    /// Drop the extra digits (round towards zero)
+    Truncate,
+    /// Round half to even (banker's rounding)
+    #[default]
+    HalfEven,
+}
 
 impl Moneys {
-    /// TODO: Get rid of all f64 arithmetic
-    ///
-    /// As for now in many arithmetic operation we use f64 as an intermediate
-    /// representation and we want to be sure that we haven't lost any
-    /// precision while doing arithmetic.
-    ///
-    /// f64 can represent exactly integers up to `2**53` (52-bit mantissa +
-    /// leading 1 bit that's not included in the mantissa).
-    ///
-    /// We also want to be able to do addition on the intermediate type and
-    /// not lose precision on the result.
-    ///
-    /// log(2**53 / 2)/log(10) = 15.65355977452702
-    ///
-    /// So that's about 15 decimal degits. Let's make it 14 to be on the safe
-    /// side.
-    /// This value is checked in test later.
-    const MAX_EXACT_UNITS: u64 = 100_000_000_000_000;
-
-    /// How much is one moneys worth in f64
-    const ONE_UNIT_AS_AMOUNT: f64 = 0.0001;
-
-    /// Maximum Moneys value
-    pub const MAX: Self = Self(Self::MAX_EXACT_UNITS);
-
-    /// Constant for convenience
-    pub const ZERO: Self = Self(0);
-
-    pub const fn new(units: u64) -> Self {
-        Self(units)
+    /// Number of decimal places used unless configured otherwise
+    pub const DEFAULT_SCALE: u8 = 4;
+
+    /// Maximum Moneys value, larger than or equal to any value of any scale; deployments can
+    /// set a lower limit on account balances (see `ProcessorBuilder::max_balance`)
+    pub const MAX: Self = Self::with_scale(u128::MAX, 0);
+
+    /// Constant for convenience, adding it to a value of any scale keeps the scale
+    pub const ZERO: Self = Self::with_scale(0, 0);
+
+    /// Amount of ten-thousandths, the default scale
+    pub const fn new(units: u128) -> Self {
+        Self::with_scale(units, Self::DEFAULT_SCALE)
     }
 
-    pub fn add(&self, other: Self) -> Result<Self> {
-        let sum = self.0 + other.0;
-        if sum > Self::MAX_EXACT_UNITS {
-            bail!("addition overflow");
+    /// Amount of `units` with `scale` decimal places
+    pub const fn with_scale(units: u128, scale: u8) -> Self {
+        Self { units, scale }
+    }
+
+    pub fn scale(&self) -> u8 {
+        self.scale
+    }
+
+    /// Units of this value at larger `scale`, `None` if they don't fit
+    fn units_at(&self, scale: u8) -> Option<u128> {
+        10u128
+            .checked_pow(u32::from(scale - self.scale))
+            .and_then(|factor| self.units.checked_mul(factor))
+    }
+
+    /// Units of both values at the larger of their scales
+    fn align(&self, other: Self) -> Result<(u128, u128, u8)> {
+        let scale = self.scale.max(other.scale);
+        match (self.units_at(scale), other.units_at(scale)) {
+            (Some(a), Some(b)) => Ok((a, b, scale)),
+            _ => Err(MoneysError::Overflow),
         }
-        Ok(Self(sum))
+    }
+
+    /// Same value with `scale` decimal places, fails if it doesn't fit or digits would be lost
+    pub fn rescale(&self, scale: u8) -> Result<Self> {
+        if scale >= self.scale {
+            let units = self.units_at(scale).ok_or(MoneysError::Overflow)?;
+            return Ok(Self::with_scale(units, scale));
+        }
+        let factor = 10u128.checked_pow(u32::from(self.scale - scale));
+        match factor {
+            Some(factor) if self.units.is_multiple_of(factor) => {
+                Ok(Self::with_scale(self.units / factor, scale))
+            }
+            None if self.units == 0 => Ok(Self::with_scale(0, scale)),
+            _ => Err(MoneysError::TooPrecise),
+        }
+    }
+
+    pub fn add(&self, other: Self) -> Result<Self> {
+        let (a, b, scale) = self.align(other)?;
+        a.checked_add(b)
+            .map(|units| Self::with_scale(units, scale))
+            .ok_or(MoneysError::Overflow)
     }
 
     pub fn sub(&self, other: Self) -> Result<Self> {
-        if self.0 < other.0 {
-            bail!("subtraction underflow");
+        let (a, b, scale) = match self.align(other) {
+            Ok(aligned) => aligned,
+            // Only the subtrahend can be too large to align, `self` is kept at its scale
+            Err(_) if self.scale >= other.scale => return Err(MoneysError::Underflow),
+            Err(error) => return Err(error),
+        };
+        a.checked_sub(b)
+            .map(|units| Self::with_scale(units, scale))
+            .ok_or(MoneysError::Underflow)
+    }
+
+    /// This value multiplied by `numerator` and divided by `denominator`, with `scale` decimal
+    /// places; digits past the last one are treated according to `rounding`. Used for currency
+    /// conversions, which would lose digits when done in two steps.
+    pub fn mul_div(
+        &self,
+        numerator: Self,
+        denominator: Self,
+        scale: u8,
+        rounding: Rounding,
+    ) -> Result<Self> {
+        if denominator.units == 0 {
+            return Err(MoneysError::Malformed);
+        }
+        // Result units are self * numerator * 10^(scale + denominator.scale) divided by
+        // denominator * 10^(self.scale + numerator.scale), only the difference of the powers
+        // is applied
+        let up = i32::from(scale) + i32::from(denominator.scale);
+        let down = i32::from(self.scale) + i32::from(numerator.scale);
+        let power = |exponent: i32| 10u128.checked_pow(exponent.unsigned_abs());
+        let (mut dividend, mut divisor) = (
+            self.units.checked_mul(numerator.units),
+            Some(denominator.units),
+        );
+        if up >= down {
+            dividend = dividend
+                .zip(power(up - down))
+                .and_then(|(a, b)| a.checked_mul(b));
+        } else {
+            divisor = divisor
+                .zip(power(down - up))
+                .and_then(|(a, b)| a.checked_mul(b));
         }
-        Ok(Self(self.0 - other.0))
+        let dividend = dividend.ok_or(MoneysError::Overflow)?;
+        let divisor = match divisor {
+            Some(divisor) => divisor,
+            // Divisor beyond u128 is more than twice as large as a dividend below 2^127, so the
+            // result is less than half a unit
+            None if dividend <= u128::MAX / 2 => {
+                return Self::round(0, dividend != 0, false, scale, rounding)
+            }
+            None => return Err(MoneysError::Overflow),
+        };
+
+        let (units, remainder) = (dividend / divisor, dividend % divisor);
+        let half = divisor - remainder;
+        Self::round(
+            units,
+            remainder != 0,
+            remainder > half || remainder == half && units % 2 == 1,
+            scale,
+            rounding,
+        )
+    }
+
+    /// Finish rounding of `units`, `inexact` if some digits were dropped and `up` if half even
+    /// rounding goes up
+    fn round(units: u128, inexact: bool, up: bool, scale: u8, rounding: Rounding) -> Result<Self> {
+        let units = match rounding {
+            _ if !inexact => units,
+            Rounding::Reject => return Err(MoneysError::TooPrecise),
+            Rounding::Truncate => units,
+            Rounding::HalfEven if up => units.checked_add(1).ok_or(MoneysError::Overflow)?,
+            Rounding::HalfEven => units,
+        };
+        Ok(Self::with_scale(units, scale))
     }
 
     pub fn less_than(&self, other: Self) -> bool {
-        self.0 < other.0
+        *self < other
     }
-}
 
-impl TryFrom<f64> for Moneys {
-    /// TODO: add special error for this error
-    type Error = anyhow::Error;
+    /// Parse decimal string (like `"12.3456"`) into `Moneys` with `scale` decimal places,
+    /// treating digits past the last one according to `rounding`.
+    pub fn parse(s: &str, scale: u8, rounding: Rounding) -> Result<Self> {
+        let (negative, s) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+        if whole.is_empty() && fraction.is_empty()
+            || !whole
+                .bytes()
+                .chain(fraction.bytes())
+                .all(|b| b.is_ascii_digit())
+        {
+            return Err(MoneysError::Malformed);
+        }
+
+        let push_digit = |units: u128, digit: u8| {
+            units
+                .checked_mul(10)
+                .and_then(|units| units.checked_add(u128::from(digit - b'0')))
+        };
 
-    fn try_from(num: f64) -> Result<Self, Self::Error> {
-        let units = (num / Self::ONE_UNIT_AS_AMOUNT).round();
+        let mut units: u128 = 0;
+        for digit in whole.bytes() {
+            units = push_digit(units, digit).ok_or(MoneysError::Overflow)?;
+        }
+        let fraction = fraction.as_bytes();
+        for i in 0..usize::from(scale) {
+            let digit = fraction.get(i).copied().unwrap_or(b'0');
+            units = push_digit(units, digit).ok_or(MoneysError::Overflow)?;
+        }
 
-        if units < 0.0 {
-            bail!("negative money value");
+        let rest = fraction.get(usize::from(scale)..).unwrap_or(&[]);
+        if rest.iter().any(|&digit| digit != b'0') {
+            let round_up = match rounding {
+                Rounding::Reject => return Err(MoneysError::TooPrecise),
+                Rounding::Truncate => false,
+                Rounding::HalfEven => {
+                    let tail_is_zero = rest[1..].iter().all(|&digit| digit == b'0');
+                    match rest[0] {
+                        b'5' if tail_is_zero => units % 2 == 1,
+                        digit => digit >= b'5',
+                    }
+                }
+            };
+            if round_up {
+                units = units.checked_add(1).ok_or(MoneysError::Overflow)?;
+            }
         }
-        if units > (Self::MAX_EXACT_UNITS as f64) {
-            bail!("money value too big");
+
+        if negative && units != 0 {
+            return Err(MoneysError::Negative);
         }
-        Ok(Moneys::new(units as u64))
+        Ok(Self::with_scale(units, scale))
     }
 }
 
-/// TODO: Implement integer parser for `Moneys` and get rid of all f64 arithmetic.
-///
-/// TODO: By parsing values as f64, we implicitely interpret values past the fourth decimal place,
-/// because we keep rounding values (ie. 0.00005 should be interpreted as 0.0000, but we keep
-/// interpreting it as 0.0001 because of rounding; at the same time we cannot just floor the
-/// values, because that would make (inexact) 0.0002 value which is represented as 0.0001999999...
-/// into 0.0001).
+/// Exact parsing, the scale is the number of decimal places written
 impl TryFrom<&str> for Moneys {
-    /// TODO: add special error for this error
-    type Error = anyhow::Error;
+    type Error = MoneysError;
+
+    fn try_from(s: &str) -> Result<Self> {
+        let decimal_places = s.split_once('.').map_or(0, |(_, fraction)| fraction.len());
+        let scale = u8::try_from(decimal_places).map_err(|_| MoneysError::TooPrecise)?;
+        Moneys::parse(s, scale, Rounding::Reject)
+    }
+}
+
+impl FromStr for Moneys {
+    type Err = MoneysError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Moneys::try_from(s)
+    }
+}
+
+/// Values are compared by the amount of money, regardless of scale
+impl Ord for Moneys {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let scale = self.scale.max(other.scale);
+        match (self.units_at(scale), other.units_at(scale)) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            // Value that doesn't fit at the larger scale is larger than anything that does
+            (None, _) => Ordering::Greater,
+            (_, None) => Ordering::Less,
+        }
+    }
+}
+
+impl PartialOrd for Moneys {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
 
-    fn try_from(s: &str) -> Result<Self, Self::Error> {
-        let num: f64 = s.parse()?;
-        Moneys::try_from(num)
+impl PartialEq for Moneys {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
     }
 }
 
-impl From<Moneys> for f64 {
-    fn from(m: Moneys) -> Self {
-        (m.0 as f64) * Moneys::ONE_UNIT_AS_AMOUNT
+impl Eq for Moneys {}
+
+impl fmt::Display for Moneys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (whole, fraction) = match 10u128.checked_pow(u32::from(self.scale)) {
+            Some(factor) => (self.units / factor, self.units % factor),
+            None => (0, self.units),
+        };
+        if self.scale == 0 {
+            write!(f, "{}", whole)
+        } else {
+            write!(
+                f,
+                "{}.{:0width$}",
+                whole,
+                fraction,
+                width = usize::from(self.scale)
+            )
+        }
+    }
+}
+
+impl Serialize for Moneys {
+    fn serialize<S: Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Moneys {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(d)?;
+        Moneys::try_from(s.as_str()).map_err(de::Error::custom)
+    }
+}
+
+impl FromStr for Rounding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "reject" => Ok(Rounding::Reject),
+            "truncate" => Ok(Rounding::Truncate),
+            "half-even" => Ok(Rounding::HalfEven),
+            _ => Err(format!("unknown rounding mode {:?}", s)),
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::primitive::f64;
+
+    /// Ten-thousandths, the default scale
+    #[allow(non_snake_case)]
+    fn Moneys(units: u128) -> super::Moneys {
+        super::Moneys::new(units)
+    }
+
+    fn parse(s: &str) -> Result<super::Moneys> {
+        super::Moneys::parse(s, super::Moneys::DEFAULT_SCALE, Rounding::default())
+    }
+
+    /// Largest value with the default scale
+    const MAX: super::Moneys = super::Moneys::new(u128::MAX);
 
     #[test]
-    fn limits() {
-        assert!(
-            (Moneys::MAX_EXACT_UNITS as f64) < f64::powf(2.0, f64::from(f64::MANTISSA_DIGITS - 1)),
-            "BUG: MAX_EXACT doesn't fit twice precisely into f64"
+    fn conversion() {
+        assert_eq!(parse("-0.0").unwrap(), Moneys(0));
+        assert_eq!(parse("0.00004").unwrap(), Moneys(0));
+        assert_eq!(parse("0.00005").unwrap(), Moneys(0));
+        assert_eq!(parse("0.00015").unwrap(), Moneys(2));
+        assert_eq!(parse("0.000051").unwrap(), Moneys(1));
+        assert_eq!(parse("1").unwrap(), Moneys(10_000));
+        assert_eq!(parse("1.").unwrap(), Moneys(10_000));
+        assert_eq!(parse(".5").unwrap(), Moneys(5_000));
+        assert_eq!(parse("0123123").unwrap(), Moneys(1_231_230_000));
+        assert_eq!(
+            parse("34028236692093846346337460743176821.1455").unwrap(),
+            MAX
+        );
+        assert_eq!(
+            parse("34028236692093846346337460743176821.1456"),
+            Err(MoneysError::Overflow)
         );
+        assert!(parse("34028236692093846346337460743176821.14555").is_err());
+        assert_eq!(parse("-0.0001"), Err(MoneysError::Negative));
+        assert_eq!(parse(""), Err(MoneysError::Malformed));
+        assert!(parse(".").is_err());
+        assert!(parse("1e5").is_err());
+        assert!(parse("1.2.3").is_err());
+        assert!(parse(" 1").is_err());
+
+        // Exact conversion keeps the written scale
+        let exact = super::Moneys::try_from("1.50").unwrap();
+        assert_eq!(exact.scale(), 2);
+        assert_eq!(exact, Moneys(15_000));
+        assert_eq!(super::Moneys::try_from("1500").unwrap().scale(), 0);
     }
 
     #[test]
-    fn conversion() {
-        assert_eq!(Moneys::try_from("-0.0").unwrap(), Moneys(0));
-        assert_eq!(Moneys::try_from("0.00004").unwrap(), Moneys(0));
-        assert_eq!(Moneys::try_from("0.00005").unwrap(), Moneys(1));
-        assert_eq!(Moneys::try_from("1").unwrap(), Moneys(10_000));
-        assert_eq!(Moneys::try_from("10000000000.0000").unwrap(), Moneys::MAX);
-        assert!(Moneys::try_from("10000000000.0001").is_err());
+    fn mul_div() {
+        let rate = super::Moneys::with_scale(10843, 4);
+        let one = super::Moneys::with_scale(1, 0);
+        let convert = |units, numerator, denominator, rounding| {
+            Moneys(units).mul_div(numerator, denominator, 2, rounding)
+        };
+        assert_eq!(
+            convert(100_000, rate, one, Rounding::HalfEven),
+            Ok(super::Moneys::with_scale(1084, 2))
+        );
+        assert_eq!(
+            convert(
+                100_000,
+                super::Moneys::with_scale(15, 1),
+                one,
+                Rounding::Reject
+            ),
+            Ok(super::Moneys::with_scale(1500, 2))
+        );
+        assert_eq!(
+            convert(100_050, rate, one, Rounding::HalfEven),
+            Ok(super::Moneys::with_scale(1085, 2))
+        );
+        assert_eq!(
+            convert(100_050, rate, one, Rounding::Truncate),
+            Ok(super::Moneys::with_scale(1084, 2))
+        );
+        assert_eq!(
+            convert(100_050, rate, one, Rounding::Reject),
+            Err(MoneysError::TooPrecise)
+        );
+        // Inverse rate, 10 / 1.0843 = 9.2225...
+        assert_eq!(
+            convert(100_000, one, rate, Rounding::HalfEven),
+            Ok(super::Moneys::with_scale(922, 2))
+        );
+        // Ties go to even
+        let half = super::Moneys::with_scale(5, 1);
+        assert_eq!(
+            super::Moneys::with_scale(5, 2).mul_div(half, one, 2, Rounding::HalfEven),
+            Ok(super::Moneys::with_scale(2, 2))
+        );
+        assert_eq!(
+            super::Moneys::with_scale(7, 2).mul_div(half, one, 2, Rounding::HalfEven),
+            Ok(super::Moneys::with_scale(4, 2))
+        );
+        assert_eq!(
+            MAX.mul_div(rate, one, 4, Rounding::HalfEven),
+            Err(MoneysError::Overflow)
+        );
+        assert_eq!(
+            Moneys(1).mul_div(one, super::Moneys::ZERO, 4, Rounding::HalfEven),
+            Err(MoneysError::Malformed)
+        );
+    }
+
+    #[test]
+    fn rounding() {
+        let parse = |s, rounding| super::Moneys::parse(s, 4, rounding).ok();
+        assert_eq!(
+            super::Moneys::parse("1.00015", 4, Rounding::Reject),
+            Err(MoneysError::TooPrecise)
+        );
+        assert_eq!(parse("1.00010", Rounding::Reject), Some(Moneys(10_001)));
+        assert_eq!(parse("1.00019", Rounding::Truncate), Some(Moneys(10_001)));
+        assert_eq!(parse("1.00015", Rounding::HalfEven), Some(Moneys(10_002)));
+        assert_eq!(parse("1.00025", Rounding::HalfEven), Some(Moneys(10_002)));
+        assert_eq!(
+            parse("1.000250001", Rounding::HalfEven),
+            Some(Moneys(10_003))
+        );
+        assert_eq!(
+            parse("1.00024999", Rounding::HalfEven),
+            Some(Moneys(10_002))
+        );
+    }
+
+    #[test]
+    fn scales() {
+        let parse = |s, scale| super::Moneys::parse(s, scale, Rounding::Reject);
+        let yen = parse("1500", 0).unwrap();
+        assert_eq!(yen.to_string(), "1500");
+        assert_eq!(parse("1500.5", 0), Err(MoneysError::TooPrecise));
+        let dollars = parse("12.34", 2).unwrap();
+        assert_eq!(dollars.to_string(), "12.34");
+        let tokens = parse("0.000000000000000001", 18).unwrap();
+        assert_eq!(tokens, super::Moneys::with_scale(1, 18));
+        assert_eq!(tokens.to_string(), "0.000000000000000001");
+        assert_eq!(
+            super::Moneys::parse("1.005", 2, Rounding::HalfEven)
+                .unwrap()
+                .to_string(),
+            "1.00"
+        );
+
+        // Mixed scales keep all digits
+        let sum = dollars.add(tokens).unwrap();
+        assert_eq!(sum.to_string(), "12.340000000000000001");
+        assert_eq!(sum.sub(tokens).unwrap(), dollars);
+        assert!(dollars.less_than(sum));
+        assert_eq!(dollars.rescale(4).unwrap().to_string(), "12.3400");
+        assert_eq!(sum.rescale(2), Err(MoneysError::TooPrecise));
+        assert_eq!(
+            super::Moneys::with_scale(u128::MAX, 2).rescale(3),
+            Err(MoneysError::Overflow)
+        );
+        assert!(super::Moneys::with_scale(u128::MAX, 30) < super::Moneys::MAX);
+        assert!(super::Moneys::ZERO < tokens);
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Moneys(0).to_string(), "0.0000");
+        assert_eq!(Moneys(1).to_string(), "0.0001");
+        assert_eq!(Moneys(15_000).to_string(), "1.5000");
+        assert_eq!(MAX.to_string(), "34028236692093846346337460743176821.1455");
+        assert_eq!(
+            super::Moneys::MAX.to_string(),
+            "340282366920938463463374607431768211455"
+        );
+        assert_eq!(parse(MAX.to_string().as_str()).unwrap(), MAX);
+        assert_eq!(
+            super::Moneys::try_from(MAX.to_string().as_str()).unwrap(),
+            MAX
+        );
     }
 
     /// Test that we can do aritmetic just below the limit and that it is exact
     #[test]
     fn exact() {
-        let a = Moneys::try_from("9999999999.9999").unwrap();
-        let b = Moneys::try_from("0.0001").unwrap();
+        let a = parse("34028236692093846346337460743176821.1454").unwrap();
+        let b = parse("0.0001").unwrap();
         let c = a.add(b).unwrap();
-        assert_eq!(a, Moneys(99999999999999));
+        assert_eq!(a, Moneys(u128::MAX - 1));
         assert_eq!(b, Moneys(1));
-        assert_eq!(c, Moneys::MAX);
-        assert_eq!(f64::from(c), 10000000000.0000);
+        assert_eq!(c, MAX);
+        assert_eq!(c.to_string(), "34028236692093846346337460743176821.1455");
     }
 
     #[test]
     fn add() {
         assert_eq!(Moneys(3).add(Moneys(5)).unwrap(), Moneys(8));
         assert_eq!(Moneys(10).add(Moneys(0)).unwrap(), Moneys(10));
-        assert!(Moneys::MAX.add(Moneys(1)).is_err());
-        assert_eq!(
-            Moneys(Moneys::MAX_EXACT_UNITS - 1).add(Moneys(1)).unwrap(),
-            Moneys::MAX
-        );
+        assert_eq!(MAX.add(Moneys(1)), Err(MoneysError::Overflow));
+        assert_eq!(Moneys(u128::MAX - 1).add(Moneys(1)).unwrap(), MAX);
     }
 
     #[test]
@@ -156,20 +553,19 @@ mod test {
         assert_eq!(Moneys(8).sub(Moneys(5)).unwrap(), Moneys(3));
         assert_eq!(Moneys(10).sub(Moneys(0)).unwrap(), Moneys(10));
         assert_eq!(Moneys(0).sub(Moneys(0)).unwrap(), Moneys(0));
-        assert!(Moneys(3).sub(Moneys(4)).is_err());
-        assert_eq!(Moneys::MAX.sub(Moneys::MAX).unwrap(), Moneys(0));
+        assert_eq!(Moneys(3).sub(Moneys(4)), Err(MoneysError::Underflow));
+        assert_eq!(MAX.sub(MAX).unwrap(), Moneys(0));
+        assert_eq!(MAX.sub(Moneys(u128::MAX - 1)).unwrap(), Moneys(1));
         assert_eq!(
-            Moneys::MAX
-                .sub(Moneys(Moneys::MAX_EXACT_UNITS - 1))
-                .unwrap(),
-            Moneys(1)
+            Moneys(1).sub(super::Moneys::MAX),
+            Err(MoneysError::Underflow)
         );
     }
 
     #[test]
     fn less_than() {
         assert!(Moneys(8).less_than(Moneys(10)));
-        assert!(Moneys(8).less_than(Moneys::MAX));
+        assert!(Moneys(8).less_than(MAX));
         assert!(!Moneys(10).less_than(Moneys(0)));
     }
 }
@@ -0,0 +1,403 @@
+//! Writing of accounts
+
+use crate::account::{Account, ClientId};
+use crate::io;
+use crate::moneys::Moneys;
+use anyhow::Result;
+use std::io::Write;
+use std::str::FromStr;
+
+/// Format of the accounts output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// JSON array of account objects
+    Json,
+    /// One JSON account object per line
+    Ndjson,
+    /// Aligned table for humans
+    Table,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "table" => Ok(OutputFormat::Table),
+            _ => Err(format!("unknown output format {:?}", s)),
+        }
+    }
+}
+
+/// What to order accounts by; ties are ordered by client
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SortKey {
+    #[default]
+    Client,
+    Available,
+    Held,
+    Total,
+    /// Unlocked accounts first
+    Locked,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "client" => Ok(SortKey::Client),
+            "available" => Ok(SortKey::Available),
+            "held" => Ok(SortKey::Held),
+            "total" => Ok(SortKey::Total),
+            "locked" => Ok(SortKey::Locked),
+            _ => Err(format!("unknown sort key {:?}", s)),
+        }
+    }
+}
+
+/// Which accounts to output and in what order. The default selects all accounts ordered by
+/// client.
+///
+/// Accounts are output as one row per client and currency, so the selection works on rows:
+/// sorting by amounts compares balances in single currencies and `non_zero` drops empty
+/// balances of otherwise used accounts.
+#[derive(Debug, Clone, Default)]
+pub struct AccountSelection {
+    pub sort: SortKey,
+    /// Only locked accounts
+    pub locked_only: bool,
+    /// Only accounts with some money, available or held
+    pub non_zero: bool,
+    /// Only accounts of these clients
+    pub clients: Option<Vec<ClientId>>,
+}
+
+impl AccountSelection {
+    pub fn apply(&self, accounts: Vec<Account>) -> Result<Vec<io::Account>> {
+        let mut selected = vec![];
+        for account in &accounts {
+            selected.extend(account.rows()?.into_iter().filter(|row| self.selects(row)));
+        }
+        let tie = |row: &io::Account| (row.client, row.currency.clone());
+        match self.sort {
+            SortKey::Client => selected.sort_by_key(tie),
+            SortKey::Available => selected.sort_by_key(|row| (row.available, tie(row))),
+            SortKey::Held => selected.sort_by_key(|row| (row.held, tie(row))),
+            SortKey::Total => selected.sort_by_key(|row| (row.total, tie(row))),
+            SortKey::Locked => selected.sort_by_key(|row| (row.locked, tie(row))),
+        }
+        Ok(selected)
+    }
+
+    fn selects(&self, account: &io::Account) -> bool {
+        (!self.locked_only || account.locked)
+            && (!self.non_zero || account.total != Moneys::ZERO)
+            && self
+                .clients
+                .as_ref()
+                .is_none_or(|clients| clients.contains(&account.client))
+    }
+}
+
+/// Serialize accounts in given `format`, one row per currency, in the given order
+pub fn print_accounts<W: Write>(
+    raw_writer: W,
+    accounts: &[Account],
+    format: OutputFormat,
+) -> Result<()> {
+    let mut rows = vec![];
+    for account in accounts {
+        rows.extend(account.rows()?);
+    }
+    print_rows(raw_writer, &rows, format)
+}
+
+/// Serialize account rows in given `format`, in the given order. The currency column is only
+/// there if some row isn't in the default currency, the status column if some account isn't
+/// open.
+pub fn print_rows<W: Write>(
+    mut raw_writer: W,
+    rows: &[io::Account],
+    format: OutputFormat,
+) -> Result<()> {
+    let columns = Columns {
+        currency: rows.iter().any(|row| !row.currency.is_default()),
+        status: rows.iter().any(|row| !row.status.is_open()),
+    };
+
+    match format {
+        OutputFormat::Csv => {
+            let mut writer = csv::WriterBuilder::new().from_writer(raw_writer);
+            // Not serialized, so that optional columns are either in all rows or in none
+            writer.write_record(table_header(columns))?;
+            for row in rows {
+                writer.write_record(table_row(row, columns, |locked| locked.to_string()))?;
+            }
+            writer.flush()?;
+            return Ok(());
+        }
+        OutputFormat::Json => {
+            for (i, row) in rows.iter().enumerate() {
+                raw_writer.write_all(if i == 0 { b"[\n" } else { b",\n" })?;
+                serde_json::to_writer(&mut raw_writer, row)?;
+            }
+            raw_writer.write_all(if rows.is_empty() { b"[]\n" } else { b"\n]\n" })?;
+        }
+        OutputFormat::Ndjson => {
+            for row in rows {
+                serde_json::to_writer(&mut raw_writer, row)?;
+                raw_writer.write_all(b"\n")?;
+            }
+        }
+        OutputFormat::Table => write_table(&mut raw_writer, rows, columns)?,
+    }
+    raw_writer.flush()?;
+    Ok(())
+}
+
+/// Optional columns of CSV and table output
+#[derive(Debug, Clone, Copy)]
+struct Columns {
+    currency: bool,
+    status: bool,
+}
+
+fn table_header(columns: Columns) -> Vec<String> {
+    let mut header = vec!["client"];
+    if columns.currency {
+        header.push("currency");
+    }
+    header.extend(["available", "held", "total", "locked"]);
+    if columns.status {
+        header.push("status");
+    }
+    header.into_iter().map(str::to_owned).collect()
+}
+
+fn table_row(row: &io::Account, columns: Columns, locked: fn(bool) -> String) -> Vec<String> {
+    let mut cells = vec![row.client.to_string()];
+    if columns.currency {
+        cells.push(row.currency.as_str().to_owned());
+    }
+    cells.extend([
+        row.available.to_string(),
+        row.held.to_string(),
+        row.total.to_string(),
+        locked(row.locked),
+    ]);
+    if columns.status {
+        cells.push(row.status.as_str().to_owned());
+    }
+    cells
+}
+
+fn write_table<W: Write>(writer: &mut W, rows: &[io::Account], columns: Columns) -> Result<()> {
+    let header = table_header(columns);
+    let rows: Vec<_> = rows
+        .iter()
+        .map(|row| {
+            table_row(row, columns, |locked| {
+                if locked { "yes" } else { "no" }.to_owned()
+            })
+        })
+        .collect();
+    let mut widths: Vec<_> = header.iter().map(String::len).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    for row in std::iter::once(&header).chain(&rows) {
+        // Numbers are aligned to the right, everything else to the left
+        let cells: Vec<_> = row
+            .iter()
+            .zip(&widths)
+            .enumerate()
+            .map(|(i, (cell, width))| {
+                let last = i + 1 == row.len();
+                if last {
+                    cell.clone()
+                } else if matches!(header[i].as_str(), "currency" | "locked") {
+                    format!("{:<width$}", cell, width = width)
+                } else {
+                    format!("{:>width$}", cell, width = width)
+                }
+            })
+            .collect();
+        writeln!(writer, "{}", cells.join("  "))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::currency::Currency;
+    use crate::restriction::Restriction;
+
+    fn accounts() -> Vec<Account> {
+        let default = Currency::default();
+        let locked = Account::new(10)
+            .deposit(&default, Moneys::new(1_000_000))
+            .unwrap()
+            .dispute(&default, Moneys::new(10_000))
+            .unwrap()
+            .chargeback(&default, Moneys::new(10_000), Restriction::Frozen)
+            .unwrap();
+        assert!(locked.is_locked());
+        let other = Account::new(2)
+            .deposit(&default, Moneys::new(15_000))
+            .unwrap();
+        vec![other, locked]
+    }
+
+    fn print(format: OutputFormat, accounts: &[Account]) -> String {
+        let mut buffer = vec![];
+        print_accounts(&mut buffer, accounts, format).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(
+            print(OutputFormat::Csv, &accounts()),
+            "client,available,held,total,locked\n\
+             2,1.5000,0.0000,1.5000,false\n\
+             10,99.0000,0.0000,99.0000,true\n"
+        );
+        assert_eq!(
+            print(OutputFormat::Ndjson, &accounts()),
+            "{\"client\":2,\"available\":\"1.5000\",\"held\":\"0.0000\",\"total\":\"1.5000\",\"locked\":false}\n\
+             {\"client\":10,\"available\":\"99.0000\",\"held\":\"0.0000\",\"total\":\"99.0000\",\"locked\":true}\n"
+        );
+        let json = print(OutputFormat::Json, &accounts());
+        let parsed: Vec<io::Account> = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[1].client, 10);
+        assert_eq!(print(OutputFormat::Json, &[]), "[]\n");
+        assert_eq!(
+            print(OutputFormat::Table, &accounts()),
+            [
+                "client  available    held    total  locked\n",
+                "     2     1.5000  0.0000   1.5000  no\n",
+                "    10    99.0000  0.0000  99.0000  yes\n",
+            ]
+            .concat()
+        );
+    }
+
+    #[test]
+    fn currencies() {
+        let usd = Currency::new("USD").unwrap();
+        let accounts = vec![Account::new(1)
+            .deposit(&Currency::default(), Moneys::new(10_000))
+            .unwrap()
+            .deposit(&usd, Moneys::with_scale(250, 2))
+            .unwrap()];
+        assert_eq!(
+            print(OutputFormat::Csv, &accounts),
+            "client,currency,available,held,total,locked\n\
+             1,,1.0000,0.0000,1.0000,false\n\
+             1,USD,2.50,0.00,2.50,false\n"
+        );
+        assert_eq!(
+            print(OutputFormat::Ndjson, &accounts),
+            "{\"client\":1,\"available\":\"1.0000\",\"held\":\"0.0000\",\"total\":\"1.0000\",\"locked\":false}\n\
+             {\"client\":1,\"currency\":\"USD\",\"available\":\"2.50\",\"held\":\"0.00\",\"total\":\"2.50\",\"locked\":false}\n"
+        );
+        assert_eq!(
+            print(OutputFormat::Table, &accounts),
+            [
+                "client  currency  available    held   total  locked\n",
+                "     1               1.0000  0.0000  1.0000  no\n",
+                "     1  USD            2.50    0.00    2.50  no\n",
+            ]
+            .concat()
+        );
+
+        // Status is shown once some account isn't open
+        let mut closed = Account::new(3);
+        closed.close();
+        let with_closed = vec![
+            accounts[0].clone(),
+            closed.deposit(&usd, Moneys::with_scale(0, 2)).unwrap(),
+        ];
+        assert_eq!(
+            print(OutputFormat::Table, &with_closed),
+            [
+                "client  currency  available    held   total  locked  status\n",
+                "     1               1.0000  0.0000  1.0000  no      open\n",
+                "     1  USD            2.50    0.00    2.50  no      open\n",
+                "     3  USD            0.00    0.00    0.00  no      closed\n",
+            ]
+            .concat()
+        );
+        assert_eq!(
+            print(OutputFormat::Ndjson, &with_closed[1..]),
+            "{\"client\":3,\"currency\":\"USD\",\"available\":\"0.00\",\"held\":\"0.00\",\"total\":\"0.00\",\"locked\":false,\"status\":\"closed\"}\n"
+        );
+
+        // Rows are selected and sorted one by one
+        let by_total = AccountSelection {
+            sort: SortKey::Total,
+            ..Default::default()
+        };
+        let rows = by_total.apply(accounts).unwrap();
+        assert_eq!(rows[0].total, Moneys::new(10_000));
+        assert_eq!(rows[1].currency, usd);
+    }
+
+    #[test]
+    fn selection() {
+        let default = Currency::default();
+        let account = |client, available| {
+            Account::new(client)
+                .deposit(&default, Moneys::new(available))
+                .unwrap()
+        };
+        let locked = account(4, 10)
+            .dispute(&default, Moneys::new(10))
+            .unwrap()
+            .chargeback(&default, Moneys::new(10), Restriction::Frozen)
+            .unwrap();
+        let accounts = vec![account(3, 5), account(1, 20), account(2, 5), locked.clone()];
+        let clients = |selection: AccountSelection| -> Vec<ClientId> {
+            selection
+                .apply(accounts.clone())
+                .unwrap()
+                .iter()
+                .map(|row| row.client)
+                .collect()
+        };
+
+        assert_eq!(clients(AccountSelection::default()), vec![1, 2, 3, 4]);
+        let by_total = AccountSelection {
+            sort: SortKey::Total,
+            ..Default::default()
+        };
+        assert_eq!(clients(by_total), vec![4, 2, 3, 1]);
+        let by_locked = AccountSelection {
+            sort: SortKey::Locked,
+            ..Default::default()
+        };
+        assert_eq!(clients(by_locked), vec![1, 2, 3, 4]);
+        let locked_only = AccountSelection {
+            locked_only: true,
+            ..Default::default()
+        };
+        assert_eq!(clients(locked_only), vec![4]);
+        let non_zero = AccountSelection {
+            non_zero: true,
+            clients: Some(vec![4, 3]),
+            ..Default::default()
+        };
+        assert_eq!(clients(non_zero), vec![3]);
+    }
+}
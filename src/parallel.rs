@@ -0,0 +1,365 @@
+//! Processing sharded by client among several threads
+//!
+//! The calling thread reads the input and routes commands to worker threads by client, so that
+//! every worker owns accounts of its clients and transactions they made, and commands of every
+//! client are executed in input order.
+//!
+//! Transaction IDs are shared among all clients though. The router remembers which shard
+//! keeps the latest transaction with given ID. When another shard gets a command referencing
+//! that ID, the router borrows the transaction from the owning shard (after it executed
+//! everything routed to it before) and passes it along with the command, so that the command
+//! is executed exactly like it would be by a single processor. Changes of the borrowed
+//! transaction are handed back to its shard afterwards.
+//!
+//! Transfers (and disputes of transfers) change accounts of two clients. When the recipient
+//! belongs to a different shard, the router borrows the recipient's account from that shard for
+//! the duration of the command, waiting for both shards, so the result is again the same as
+//! with a single processor, just slower.
+//!
+//! A batch is executed by the shard of its first client, which borrows accounts of all other
+//! clients the batch involves the same way. Transactions it creates stay with that shard.
+
+use crate::account::{Account, ClientId};
+use crate::input::{self, InputFormat, Row};
+use crate::io::{self, CommandType};
+use crate::processor::{self, Processor, Transaction, TransactionId};
+use crate::rejects::RejectSink;
+use anyhow::{anyhow, bail, Result};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::mpsc;
+use std::thread;
+
+/// How many messages may wait for every worker
+const QUEUE_LENGTH: usize = 1024;
+
+/// Most shards the router can keep track of, see `Owners`
+const MAX_SHARDS: usize = 0x7f;
+
+/// Transaction IDs per page of `Owners`
+const PAGE_SIZE: usize = 1 << 16;
+
+/// For every transaction ID, the shard that got the latest command creating a transaction with
+/// that ID (anything but disputes) and whether the ID was used by a transfer, whose disputes may
+/// involve a client of another shard. Every ID takes one byte, `0x80` marks transfers and the
+/// rest is the shard plus one. Pages of IDs are allocated when first used, so memory use is
+/// bounded by the range of IDs used, not by how many transactions there are.
+struct Owners {
+    pages: Vec<Option<Box<[u8]>>>,
+}
+
+impl Owners {
+    const TRANSFER: u8 = 0x80;
+
+    fn new() -> Self {
+        let pages = TransactionId::MAX as usize / PAGE_SIZE + 1;
+        Self {
+            pages: (0..pages).map(|_| None).collect(),
+        }
+    }
+
+    fn get(&self, tx: TransactionId) -> u8 {
+        let tx = tx as usize;
+        self.pages[tx / PAGE_SIZE]
+            .as_ref()
+            .map_or(0, |page| page[tx % PAGE_SIZE])
+    }
+
+    fn entry(&mut self, tx: TransactionId) -> &mut u8 {
+        let tx = tx as usize;
+        let page =
+            self.pages[tx / PAGE_SIZE].get_or_insert_with(|| vec![0; PAGE_SIZE].into_boxed_slice());
+        &mut page[tx % PAGE_SIZE]
+    }
+
+    fn owner(&self, tx: TransactionId) -> Option<usize> {
+        match self.get(tx) & !Self::TRANSFER {
+            0 => None,
+            shard => Some(usize::from(shard) - 1),
+        }
+    }
+
+    fn set_owner(&mut self, tx: TransactionId, shard: usize) {
+        let entry = self.entry(tx);
+        *entry = (*entry & Self::TRANSFER) | (shard + 1) as u8;
+    }
+
+    fn is_transfer(&self, tx: TransactionId) -> bool {
+        self.get(tx) & Self::TRANSFER != 0
+    }
+
+    fn set_transfer(&mut self, tx: TransactionId) {
+        *self.entry(tx) |= Self::TRANSFER;
+    }
+}
+
+enum Message {
+    /// Execute a batch, or a single row, with transactions it references that are kept by
+    /// different shards; these are sent back afterwards
+    Execute(
+        Vec<Row>,
+        HashMap<TransactionId, Transaction>,
+        Option<mpsc::Sender<HashMap<TransactionId, Transaction>>>,
+    ),
+    Query(
+        TransactionId,
+        mpsc::Sender<std::io::Result<Option<Transaction>>>,
+    ),
+    /// Hand over account of a client to the router, for a transfer executed by another shard
+    Take(ClientId, mpsc::Sender<Option<Account>>),
+    /// Take back account handed over before
+    Put(Account),
+    /// Take back transaction borrowed before, with its changes
+    PutTransaction(TransactionId, Transaction),
+}
+
+fn shard_of(client: ClientId, shards: usize) -> usize {
+    usize::from(client) % shards
+}
+
+/// Whether `command` creates a transaction with its ID
+fn creates(command: &io::Command) -> bool {
+    command.command_type.is_admin()
+        || matches!(
+            command.command_type,
+            CommandType::Deposit
+                | CommandType::Withdrawal
+                | CommandType::Convert
+                | CommandType::Transfer
+        )
+}
+
+fn worker(
+    mut processor: Processor,
+    messages: mpsc::Receiver<Message>,
+    rejections: mpsc::Sender<io::Rejection>,
+    verbose: bool,
+) -> Result<Processor> {
+    for message in messages {
+        let results = match message {
+            Message::Execute(rows, mut borrowed, reply) => {
+                let results = processor.execute_rows(&rows, &mut borrowed);
+                if let Some(reply) = reply {
+                    let _ = reply.send(borrowed);
+                }
+                rows.into_iter().zip(results).collect::<Vec<_>>()
+            }
+            Message::Query(tx, reply) => {
+                // Router might have given up already
+                let _ = reply.send(processor.transaction(tx));
+                continue;
+            }
+            Message::Take(client, reply) => {
+                let _ = reply.send(processor.take_account(client));
+                continue;
+            }
+            Message::Put(account) => {
+                processor.put_account(account);
+                continue;
+            }
+            Message::PutTransaction(tx, transaction) => {
+                processor.put_transaction(tx, transaction)?;
+                continue;
+            }
+        };
+        for (row, ret) in results {
+            if verbose {
+                println!("command: {:?}\nresult: {:?}", row.command, ret);
+            }
+            if let Err(error) = ret {
+                // Nobody listens only if the router failed
+                let _ = rejections.send(row.rejection(&error));
+            }
+        }
+    }
+    Ok(processor)
+}
+
+/// Stream input file in given `format` (detected when `None`) through `processors`, each of
+/// them executing commands of a part of clients in its own thread. Returns the processors
+/// afterwards, so that their accounts can be collected. Rejected commands are reported to
+/// `rejects` in input order once the whole input is processed, they are kept in memory until
+/// then.
+pub fn run_parallel<R: std::io::Read>(
+    processors: Vec<Processor>,
+    raw_reader: R,
+    format: Option<InputFormat>,
+    verbose: bool,
+    rejects: Option<&mut dyn RejectSink>,
+) -> Result<Vec<Processor>> {
+    let shards = processors.len();
+    assert!(shards > 0, "at least one processor is needed");
+    if shards > MAX_SHARDS {
+        bail!("at most {} shards are supported", MAX_SHARDS);
+    }
+    let (rejections_sender, rejections) = mpsc::channel();
+    let (senders, workers): (Vec<_>, Vec<_>) = processors
+        .into_iter()
+        .map(|processor| {
+            let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+            let rejections = rejections_sender.clone();
+            let worker = thread::spawn(move || worker(processor, receiver, rejections, verbose));
+            (sender, worker)
+        })
+        .collect();
+    drop(rejections_sender);
+
+    // Shards reject commands in any order
+    let mut buffered = vec![];
+    let mut report = |rejection: io::Rejection| {
+        if rejects.is_some() {
+            buffered.push(rejection);
+        }
+    };
+    let send = |shard: usize, message| {
+        senders[shard]
+            .send(message)
+            .map_err(|_| anyhow!("worker thread {} failed", shard))
+    };
+    let query = |shard: usize, tx| -> Result<Option<Transaction>> {
+        let (reply_sender, reply) = mpsc::channel();
+        send(shard, Message::Query(tx, reply_sender))?;
+        Ok(reply
+            .recv()
+            .map_err(|_| anyhow!("worker thread {} failed", shard))??)
+    };
+    let take = |shard: usize, client| -> Result<Option<Account>> {
+        let (reply_sender, reply) = mpsc::channel();
+        send(shard, Message::Take(client, reply_sender))?;
+        reply
+            .recv()
+            .map_err(|_| anyhow!("worker thread {} failed", shard))
+    };
+
+    let mut owners = Owners::new();
+    let result = input::rows(raw_reader, format).and_then(|rows| {
+        for batch in input::batches(rows) {
+            let batch = match input::parsed(batch?) {
+                Ok(batch) => batch,
+                Err(rejections) => {
+                    for rejection in rejections {
+                        report(rejection);
+                    }
+                    continue;
+                }
+            };
+            // The whole batch is executed by one shard
+            let shard = shard_of(batch[0].command.client, shards);
+            // Transactions kept by other shards, with their owners
+            let mut borrowed = HashMap::new();
+            let mut lenders = HashMap::new();
+            for row in &batch {
+                let tx = row.command.tx;
+                match owners.owner(tx) {
+                    Some(owner) if owner != shard && !lenders.contains_key(&tx) => {
+                        if let Some(transaction) = query(owner, tx)? {
+                            borrowed.insert(tx, transaction);
+                            lenders.insert(tx, owner);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            // Clients of other shards whose accounts the batch changes
+            let mut clients = BTreeSet::new();
+            for row in &batch {
+                let tx = row.command.tx;
+                // Only the stored transfer knows the recipient affected by its dispute
+                let transaction = if !owners.is_transfer(tx) || creates(&row.command) {
+                    None
+                } else if let Some(transaction) = borrowed.get(&tx) {
+                    Some(transaction.clone())
+                } else {
+                    query(shard, tx)?
+                };
+                clients.insert(row.command.client);
+                clients.extend(processor::counterparty(&row.command, transaction.as_ref()));
+                if creates(&row.command) && !lenders.contains_key(&tx) {
+                    owners.set_owner(tx, shard);
+                }
+                if row.command.command_type == CommandType::Transfer {
+                    owners.set_transfer(tx);
+                }
+            }
+            clients.retain(|&client| shard_of(client, shards) != shard);
+
+            for &client in &clients {
+                if let Some(account) = take(shard_of(client, shards), client)? {
+                    send(shard, Message::Put(account))?;
+                }
+            }
+            if borrowed.is_empty() {
+                send(shard, Message::Execute(batch, borrowed, None))?;
+            } else {
+                let (reply_sender, reply) = mpsc::channel();
+                send(
+                    shard,
+                    Message::Execute(batch, borrowed.clone(), Some(reply_sender)),
+                )?;
+                let changed = reply
+                    .recv()
+                    .map_err(|_| anyhow!("worker thread {} failed", shard))?;
+                for (tx, transaction) in changed {
+                    if borrowed.get(&tx) != Some(&transaction) {
+                        send(lenders[&tx], Message::PutTransaction(tx, transaction))?;
+                    }
+                }
+            }
+            for &client in &clients {
+                if let Some(account) = take(shard, client)? {
+                    send(shard_of(client, shards), Message::Put(account))?;
+                }
+            }
+
+            for rejection in rejections.try_iter() {
+                report(rejection);
+            }
+        }
+        Ok(())
+    });
+
+    // Let workers finish even if reading failed
+    drop(senders);
+    let processors = workers
+        .into_iter()
+        .map(|worker| {
+            worker
+                .join()
+                .map_err(|_| anyhow!("worker thread panicked"))?
+        })
+        .collect::<Result<Vec<_>>>()?;
+    result?;
+    for rejection in rejections.try_iter() {
+        report(rejection);
+    }
+    if let Some(rejects) = rejects {
+        buffered.sort_by_key(|rejection| rejection.line);
+        for rejection in &buffered {
+            rejects.reject(rejection)?;
+        }
+    }
+
+    Ok(processors)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn owners() {
+        let mut owners = Owners::new();
+        assert_eq!(owners.owner(TransactionId::MAX), None);
+        owners.set_owner(TransactionId::MAX, MAX_SHARDS - 1);
+        assert_eq!(owners.owner(TransactionId::MAX), Some(MAX_SHARDS - 1));
+        assert!(!owners.is_transfer(TransactionId::MAX));
+
+        owners.set_transfer(1);
+        assert_eq!(owners.owner(1), None);
+        owners.set_owner(1, 0);
+        owners.set_owner(1, 2);
+        assert_eq!(owners.owner(1), Some(2));
+        assert!(owners.is_transfer(1));
+        assert_eq!(owners.owner(2), None);
+        assert_eq!(owners.pages.iter().flatten().count(), 2);
+    }
+}
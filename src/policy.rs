@@ -0,0 +1,122 @@
+//! Business rules that differ between partner banks
+//!
+//! `Processor` consults its `Policy` wherever the rules aren't fixed by bookkeeping: which
+//! commands restricted accounts accept, which transactions can be disputed and how often, and
+//! what happens to a dispute of more money than is available. `StandardPolicy` keeps the rules
+//! described in the README, `StrictPolicy` and `LenientPolicy` are alternatives.
+
+use crate::io::CommandType;
+use crate::processor::Transaction;
+use crate::restriction::{Restriction, RestrictionPolicy};
+
+/// What to do with a dispute that would hold more money than is available. Balances can't be
+/// negative, so the dispute can't hold money that was already spent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisputeShortfall {
+    /// Refuse the dispute with `insufficient_funds`
+    Reject,
+    /// Dispute only what's available, the rest can be disputed later
+    HoldAvailable,
+}
+
+/// Rules the processor consults at every decision point. Provided methods follow
+/// `StandardPolicy`.
+pub trait Policy: Send {
+    /// Which commands restricted accounts accept
+    fn restrictions(&self) -> &RestrictionPolicy;
+
+    /// Whether an account restricted with `restriction` accepts command of `command_type`;
+    /// admin commands are accepted regardless
+    fn allows(&self, restriction: Restriction, command_type: CommandType) -> bool {
+        self.restrictions().allows(restriction, command_type)
+    }
+
+    /// Whether `transaction` can be disputed; disputes opened before can always be settled
+    fn disputable(&self, _transaction: &Transaction) -> bool {
+        true
+    }
+
+    /// Whether amount of `transaction` that was resolved can be disputed again
+    fn redisputable(&self, _transaction: &Transaction) -> bool {
+        true
+    }
+
+    fn dispute_shortfall(&self) -> DisputeShortfall {
+        DisputeShortfall::Reject
+    }
+}
+
+/// Deposits, withdrawals and transfers can be disputed again after resolve, disputes can't
+/// hold more than is available, and restrictions allow what `RestrictionPolicy` says
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StandardPolicy {
+    restrictions: RestrictionPolicy,
+}
+
+impl StandardPolicy {
+    pub fn new(restrictions: RestrictionPolicy) -> Self {
+        Self { restrictions }
+    }
+}
+
+impl Policy for StandardPolicy {
+    fn restrictions(&self) -> &RestrictionPolicy {
+        &self.restrictions
+    }
+}
+
+/// Only deposits can be disputed, and only once, and restricted accounts accept nothing but
+/// admin commands unless `RestrictionPolicy` says otherwise
+#[derive(Debug, Clone, PartialEq)]
+pub struct StrictPolicy {
+    restrictions: RestrictionPolicy,
+}
+
+impl StrictPolicy {
+    pub fn new(restrictions: RestrictionPolicy) -> Self {
+        Self { restrictions }
+    }
+}
+
+impl Default for StrictPolicy {
+    fn default() -> Self {
+        Self::new(RestrictionPolicy::none())
+    }
+}
+
+impl Policy for StrictPolicy {
+    fn restrictions(&self) -> &RestrictionPolicy {
+        &self.restrictions
+    }
+
+    fn disputable(&self, transaction: &Transaction) -> bool {
+        matches!(transaction, Transaction::DepositTransaction { .. })
+    }
+
+    fn redisputable(&self, _transaction: &Transaction) -> bool {
+        false
+    }
+}
+
+/// Like `StandardPolicy`, but dispute of money that was already spent holds what's left
+/// instead of being refused
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LenientPolicy {
+    restrictions: RestrictionPolicy,
+}
+
+impl LenientPolicy {
+    pub fn new(restrictions: RestrictionPolicy) -> Self {
+        Self { restrictions }
+    }
+}
+
+impl Policy for LenientPolicy {
+    fn restrictions(&self) -> &RestrictionPolicy {
+        &self.restrictions
+    }
+
+    fn dispute_shortfall(&self) -> DisputeShortfall {
+        DisputeShortfall::HoldAvailable
+    }
+}
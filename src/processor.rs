@@ -1,169 +1,1966 @@
 //! Transaction management
 
-use crate::account::{Account, ClientId};
-use crate::io::{Command, CommandType};
-use crate::moneys::Moneys;
-use anyhow::{anyhow, bail, ensure, Result};
-use std::collections::HashMap;
+use crate::account::{Account, Balance, ClientId};
+use crate::currency::Currency;
+use crate::error::{BatchError, TxError};
+use crate::input::Row;
+use crate::io::{Command, CommandType, Rejection};
+use crate::journal::{self, Journal};
+use crate::moneys::{Moneys, MoneysError, Rounding};
+use crate::policy::{DisputeShortfall, Policy, StandardPolicy};
+use crate::rates::{self, Rate, RateTable};
+use crate::rejects::RejectSink;
+use crate::restriction::{Restriction, RestrictionReason};
+use crate::snapshot;
+use crate::store::{MemoryStore, TransactionStore};
+use anyhow::{bail, Context as _};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{BufRead, Write};
+
+type Result<T, E = TxError> = std::result::Result<T, E>;
 
 pub type TransactionId = u32;
 
-#[derive(Debug, Clone, Copy, PartialEq)]
-enum DepositTransactionState {
-    Deposited,
-    Disputed,
-    ChargedBack,
+/// Portions of transaction amount that went through the dispute process. Several partial
+/// disputes can be open at once, they are tracked as a single sum.
+///
+/// Deposits and withdrawals share the bookkeeping but not the effect on the account: disputed
+/// deposit is held from available funds, while disputed withdrawal is held as a pending credit
+/// which is returned to available funds on chargeback.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct DisputedAmounts {
+    /// Currently disputed (open disputes)
+    disputed: Moneys,
+    /// Total resolved so far, resolved amount can be disputed again
+    resolved: Moneys,
+    /// Total charged back so far
+    charged_back: Moneys,
 }
 
-#[derive(Debug, Clone)]
-enum Transaction {
+/// Executed deposit, withdrawal, conversion or transfer, as kept in `TransactionStore`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Transaction {
     WithdrawTransaction {
-        #[allow(dead_code)]
         client: ClientId,
-        #[allow(dead_code)]
         amount: Moneys,
+        #[serde(default)]
+        currency: Currency,
+        disputes: DisputedAmounts,
     },
     DepositTransaction {
         client: ClientId,
         amount: Moneys,
-        state: DepositTransactionState,
+        #[serde(default)]
+        currency: Currency,
+        disputes: DisputedAmounts,
+    },
+    /// Transfer is disputed by the payer, who is then in the position of a client who made a
+    /// withdrawal, while the recipient is in the position of a client who made a deposit
+    TransferTransaction {
+        client: ClientId,
+        to_client: ClientId,
+        amount: Moneys,
+        currency: Currency,
+        disputes: DisputedAmounts,
+    },
+    /// Conversions can't be disputed, they are kept for audit and to reserve the ID
+    ConversionTransaction {
+        client: ClientId,
+        /// Amount taken from `currency`
+        amount: Moneys,
+        currency: Currency,
+        /// Amount added to `to_currency`
+        converted: Moneys,
+        to_currency: Currency,
+        rate: Rate,
+    },
+    /// Admin commands can't be disputed, they are kept for audit and to reserve the ID
+    AdminTransaction {
+        client: ClientId,
+        action: AdminAction,
+        operator: String,
+        #[serde(default)]
+        reason: Option<String>,
+        /// Recipient of money swept from a closed account
+        #[serde(default)]
+        to_client: Option<ClientId>,
+        /// Money swept to `to_client`, by currency
+        #[serde(default)]
+        swept: BTreeMap<Currency, Moneys>,
+        /// Restriction set or lifted
+        #[serde(default)]
+        restriction: Option<Restriction>,
+        #[serde(default)]
+        restriction_reason: Option<RestrictionReason>,
     },
 }
 
-#[derive(Debug, Default, Clone)]
+/// Change of account made by an operator
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminAction {
+    Unlock,
+    Freeze,
+    Unfreeze,
+    Close,
+}
+
+impl Transaction {
+    /// Currency of the balance the transaction added money to or took it from; conversions
+    /// report the one they added to, admin commands don't have any
+    pub fn currency(&self) -> Option<&Currency> {
+        match self {
+            Transaction::WithdrawTransaction { currency, .. }
+            | Transaction::DepositTransaction { currency, .. }
+            | Transaction::TransferTransaction { currency, .. } => Some(currency),
+            Transaction::ConversionTransaction { to_currency, .. } => Some(to_currency),
+            Transaction::AdminTransaction { .. } => None,
+        }
+    }
+
+    /// Recipient of a transfer
+    pub fn counterparty(&self) -> Option<ClientId> {
+        match self {
+            Transaction::TransferTransaction { to_client, .. } => Some(*to_client),
+            _ => None,
+        }
+    }
+}
+
+/// Client other than the one of `command` whose account the command changes: the recipient of
+/// a new transfer, of disputed `transaction` or of money swept from a closed account
+pub(crate) fn counterparty(
+    command: &Command,
+    transaction: Option<&Transaction>,
+) -> Option<ClientId> {
+    match command.command_type {
+        CommandType::Transfer | CommandType::Close => command.to_client,
+        CommandType::Dispute | CommandType::Resolve | CommandType::Chargeback => {
+            transaction.and_then(Transaction::counterparty)
+        }
+        CommandType::Deposit
+        | CommandType::Withdrawal
+        | CommandType::Convert
+        | CommandType::Unlock
+        | CommandType::Freeze
+        | CommandType::Unfreeze => None,
+    }
+}
+
+/// Changes made by commands of a batch, not applied to the processor yet
+#[derive(Debug, Default)]
+struct Staging {
+    /// Ordered, so that the store is updated deterministically
+    accounts: BTreeMap<ClientId, Account>,
+    /// Transactions with whether they are in the store already
+    transactions: BTreeMap<TransactionId, (Transaction, bool)>,
+    entries: Vec<journal::Entry>,
+}
+
+/// New state of accounts and transaction after a command
+#[derive(Debug)]
+struct Change {
+    account: Account,
+    /// Account of the other client of a transfer
+    counterparty: Option<Account>,
+    transaction: Transaction,
+}
+
+/// Command moving transaction through the dispute process
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DisputeStep {
+    Dispute,
+    Resolve,
+    Chargeback,
+}
+
+impl DisputeStep {
+    /// Change of account of a client who made the disputed deposit, chargeback locks it with
+    /// `restriction`
+    fn apply_to_deposit(
+        self,
+        account: &Account,
+        currency: &Currency,
+        moneys: Moneys,
+        restriction: Restriction,
+    ) -> Result<Account, MoneysError> {
+        match self {
+            DisputeStep::Dispute => account.dispute(currency, moneys),
+            DisputeStep::Resolve => account.resolve(currency, moneys),
+            DisputeStep::Chargeback => account.chargeback(currency, moneys, restriction),
+        }
+    }
+
+    /// Change of account of a client who made the disputed withdrawal
+    fn apply_to_withdrawal(
+        self,
+        account: &Account,
+        currency: &Currency,
+        moneys: Moneys,
+    ) -> Result<Account, MoneysError> {
+        match self {
+            DisputeStep::Dispute => account.dispute_withdrawal(currency, moneys),
+            DisputeStep::Resolve => account.resolve_withdrawal(currency, moneys),
+            DisputeStep::Chargeback => account.chargeback_withdrawal(currency, moneys),
+        }
+    }
+}
+
+impl DisputedAmounts {
+    pub(crate) const NONE: Self = Self {
+        disputed: Moneys::ZERO,
+        resolved: Moneys::ZERO,
+        charged_back: Moneys::ZERO,
+    };
+
+    /// How much of transaction `amount` can go through given dispute step, `redisputable`
+    /// tells whether resolved amount can be disputed again
+    fn remaining(
+        &self,
+        step: DisputeStep,
+        amount: Moneys,
+        redisputable: bool,
+    ) -> Result<Moneys, MoneysError> {
+        match step {
+            DisputeStep::Dispute if redisputable => {
+                amount.sub(self.disputed)?.sub(self.charged_back)
+            }
+            DisputeStep::Dispute => amount
+                .sub(self.disputed)?
+                .sub(self.charged_back)?
+                .sub(self.resolved),
+            DisputeStep::Resolve | DisputeStep::Chargeback => Ok(self.disputed),
+        }
+    }
+
+    fn step(&self, step: DisputeStep, moneys: Moneys) -> Result<Self, MoneysError> {
+        Ok(match step {
+            DisputeStep::Dispute => Self {
+                disputed: self.disputed.add(moneys)?,
+                ..*self
+            },
+            DisputeStep::Resolve => Self {
+                disputed: self.disputed.sub(moneys)?,
+                resolved: self.resolved.add(moneys)?,
+                ..*self
+            },
+            DisputeStep::Chargeback => Self {
+                disputed: self.disputed.sub(moneys)?,
+                charged_back: self.charged_back.add(moneys)?,
+                ..*self
+            },
+        })
+    }
+}
+
 pub struct Processor {
-    accounts: HashMap<ClientId, Account>,
-    transactions: HashMap<TransactionId, Transaction>,
+    /// Ordered by client, so that everything listing accounts is deterministic
+    accounts: BTreeMap<ClientId, Account>,
+    transactions: Box<dyn TransactionStore>,
+    journal: Option<Journal>,
+    rounding: Rounding,
+    scale: u8,
+    /// Scales of currencies that don't use the default one
+    currency_scales: HashMap<Currency, u8>,
+    max_balance: Moneys,
+    rates: RateTable,
+    /// Whether conversions without time use the latest rate rather than the one in effect now
+    latest_rates: bool,
+    conversion_rounding: Rounding,
+    chargeback_restriction: Restriction,
+    policy: Box<dyn Policy>,
+}
+
+/// Builder for `Processor` configuration
+pub struct ProcessorBuilder {
+    transactions: Box<dyn TransactionStore>,
+    journal: Option<Journal>,
+    rounding: Rounding,
+    scale: u8,
+    /// Scales of currencies that don't use the default one
+    currency_scales: HashMap<Currency, u8>,
+    max_balance: Moneys,
+    rates: RateTable,
+    /// Whether conversions without time use the latest rate rather than the one in effect now
+    latest_rates: bool,
+    conversion_rounding: Rounding,
+    chargeback_restriction: Restriction,
+    policy: Box<dyn Policy>,
+}
+
+impl ProcessorBuilder {
+    /// Treat excess decimal places in amounts according to `rounding`
+    pub fn rounding(mut self, rounding: Rounding) -> Self {
+        self.rounding = rounding;
+        self
+    }
+
+    /// Keep amounts with `scale` decimal places (4 by default)
+    pub fn scale(mut self, scale: u8) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// Keep amounts in `currency` with `scale` decimal places instead of the default scale
+    pub fn currency_scale(mut self, currency: Currency, scale: u8) -> Self {
+        self.currency_scales.insert(currency, scale);
+        self
+    }
+
+    /// Refuse commands that would raise total of an account (available and held) above
+    /// `max_balance`. Commands moving money within the account or out of it are never refused
+    /// because of the limit, even if the account is above it.
+    pub fn max_balance(mut self, max_balance: Moneys) -> Self {
+        self.max_balance = max_balance;
+        self
+    }
+
+    /// Convert currencies at `rates`; without them every conversion is rejected
+    pub fn rates(mut self, rates: RateTable) -> Self {
+        self.rates = rates;
+        self
+    }
+
+    /// Convert commands without time at the latest rate of the table, even if it only applies
+    /// in the future, rather than at the rate in effect now. Results then don't depend on when
+    /// the commands are executed, so processing the same input again gives the same results.
+    pub fn latest_rates(mut self) -> Self {
+        self.latest_rates = true;
+        self
+    }
+
+    /// Treat excess decimal places of converted amounts according to `rounding` (half to even
+    /// by default)
+    pub fn conversion_rounding(mut self, rounding: Rounding) -> Self {
+        self.conversion_rounding = rounding;
+        self
+    }
+
+    /// Restrict accounts with `restriction` on chargeback (frozen by default)
+    pub fn chargeback_restriction(mut self, restriction: Restriction) -> Self {
+        self.chargeback_restriction = restriction;
+        self
+    }
+
+    /// Follow business rules of `policy` instead of `StandardPolicy`
+    pub fn policy(mut self, policy: Box<dyn Policy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Keep transactions in `store` instead of memory
+    pub fn store(mut self, store: Box<dyn TransactionStore>) -> Self {
+        self.transactions = store;
+        self
+    }
+
+    /// Record every accepted command in `journal` before changing state
+    pub fn journal(mut self, journal: Journal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    pub fn build(self) -> Processor {
+        Processor {
+            accounts: BTreeMap::new(),
+            transactions: self.transactions,
+            journal: self.journal,
+            rounding: self.rounding,
+            scale: self.scale,
+            currency_scales: self.currency_scales,
+            max_balance: self.max_balance,
+            rates: self.rates,
+            latest_rates: self.latest_rates,
+            conversion_rounding: self.conversion_rounding,
+            chargeback_restriction: self.chargeback_restriction,
+            policy: self.policy,
+        }
+    }
+}
+
+impl Default for Processor {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl fmt::Debug for Processor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Processor")
+            .field("accounts", &self.accounts)
+            .field("journal", &self.journal)
+            .field("rounding", &self.rounding)
+            .field("scale", &self.scale)
+            .field("currency_scales", &self.currency_scales)
+            .field("max_balance", &self.max_balance)
+            .field("latest_rates", &self.latest_rates)
+            .field("conversion_rounding", &self.conversion_rounding)
+            .field("chargeback_restriction", &self.chargeback_restriction)
+            .finish_non_exhaustive()
+    }
 }
 
 impl Processor {
+    pub fn builder() -> ProcessorBuilder {
+        ProcessorBuilder {
+            transactions: Box::new(MemoryStore::default()),
+            journal: None,
+            rounding: Rounding::default(),
+            scale: Moneys::DEFAULT_SCALE,
+            currency_scales: HashMap::new(),
+            max_balance: Moneys::MAX,
+            rates: RateTable::default(),
+            latest_rates: false,
+            conversion_rounding: Rounding::default(),
+            chargeback_restriction: Restriction::Frozen,
+            policy: Box::new(StandardPolicy::default()),
+        }
+    }
+
+    /// All accounts, ordered by client
     pub fn accounts(&self) -> Vec<Account> {
-        self.accounts.iter().map(|(_k, v)| v.clone()).collect()
+        self.accounts.values().cloned().collect()
+    }
+
+    /// Account of `client`, if there was any accepted command for them
+    pub fn account(&self, client: ClientId) -> Option<&Account> {
+        self.accounts.get(&client)
+    }
+
+    /// Look up executed deposit, withdrawal, conversion or transfer
+    pub fn transaction(&mut self, tx: TransactionId) -> std::io::Result<Option<Transaction>> {
+        self.transactions.get(tx)
+    }
+
+    /// Number of decimal places of amounts in `currency`
+    fn scale_of(&self, currency: &Currency) -> u8 {
+        self.currency_scales
+            .get(currency)
+            .copied()
+            .unwrap_or(self.scale)
+    }
+
+    /// Amount of `command` at the scale configured for `currency`
+    fn moneys(&self, command: &Command, currency: &Currency) -> Result<Moneys> {
+        command.get_moneys(self.scale_of(currency), self.rounding)
     }
 
+    /// Checks that conversion can be made and computes new account and transaction state
+    fn convert(&self, command: &Command, account: &Account) -> Result<Change> {
+        let (client, tx) = (command.client, command.tx);
+        let moneys_error = |error| TxError::from_moneys(error, client, tx);
+        let from = command.currency.clone().unwrap_or_default();
+        let to = command.to_currency.clone().unwrap_or_default();
+        let time = match command.time {
+            Some(time) => time,
+            None if self.latest_rates => u64::MAX,
+            None => rates::now(),
+        };
+        let rate = match self.rates.rate(&from, &to, time) {
+            Some(rate) if from != to => rate,
+            _ => {
+                return Err(TxError::NoRate {
+                    client,
+                    tx,
+                    from,
+                    to,
+                })
+            }
+        };
+        let moneys = self.moneys(command, &from)?;
+        let converted = rate
+            .convert(&from, moneys, self.scale_of(&to), self.conversion_rounding)
+            .map_err(|source| TxError::InvalidAmount { client, tx, source })?;
+        let new_account = account
+            .withdraw(&from, moneys)
+            .and_then(|account| account.deposit(&to, converted))
+            .map_err(moneys_error)?;
+        let new_transaction = Transaction::ConversionTransaction {
+            client,
+            amount: moneys,
+            currency: from,
+            converted,
+            to_currency: to,
+            rate: rate.clone(),
+        };
+        Ok(Change {
+            account: new_account,
+            counterparty: None,
+            transaction: new_transaction,
+        })
+    }
+
+    /// Checks that transfer can be made and computes new state of both accounts and the
+    /// transaction. `recipient` is `None` if the recipient has no account yet.
+    fn transfer(
+        &self,
+        command: &Command,
+        account: &Account,
+        recipient: Option<&Account>,
+    ) -> Result<Change> {
+        let (client, tx) = (command.client, command.tx);
+        let to_client = command
+            .to_client
+            .ok_or(TxError::MissingRecipient { client, tx })?;
+        if to_client == client {
+            return Err(TxError::SelfTransfer { client, tx });
+        }
+        let recipient = recipient
+            .cloned()
+            .unwrap_or_else(|| Account::new(to_client));
+        // Recipient is receiving a deposit
+        self.check_allowed(&recipient, CommandType::Deposit, tx)?;
+        let currency = command.currency.clone().unwrap_or_default();
+        let moneys = self.moneys(command, &currency)?;
+        let new_account = account
+            .withdraw(&currency, moneys)
+            .map_err(|error| TxError::from_moneys(error, client, tx))?;
+        let new_recipient = recipient
+            .deposit(&currency, moneys)
+            .map_err(|error| TxError::from_moneys(error, to_client, tx))?;
+        Ok(Change {
+            account: new_account,
+            counterparty: Some(new_recipient),
+            transaction: Transaction::TransferTransaction {
+                client,
+                to_client,
+                amount: moneys,
+                currency,
+                disputes: DisputedAmounts::NONE,
+            },
+        })
+    }
+
+    /// Checks that `transaction` can go through dispute `step` and computes new account and
+    /// transaction state. Disputes without amount apply to everything that's left. Disputed
+    /// transfer changes also the account of the recipient, `counterparty`.
     fn dispute_step(
+        &self,
+        command: &Command,
+        step: DisputeStep,
         account: &Account,
+        counterparty: Option<&Account>,
         transaction: Option<Transaction>,
-        expected_state: DepositTransactionState,
-        next_state: DepositTransactionState,
-    ) -> Result<(Moneys, Transaction)> {
-        match transaction {
-            None => bail!("transaction not found"),
-            Some(Transaction::DepositTransaction {
+    ) -> Result<Change> {
+        let (client, tx) = (command.client, command.tx);
+        let moneys_error = |error| TxError::from_moneys(error, client, tx);
+        let mut transaction = transaction.ok_or(TxError::UnknownTx { client, tx })?;
+        let recipient = transaction.counterparty();
+        let disputable = self.policy.disputable(&transaction);
+        let redisputable = self.policy.redisputable(&transaction);
+        // Dispute holds available money of the client who made the deposit or received the
+        // transfer
+        let holder = match &transaction {
+            Transaction::DepositTransaction { .. } => Some(Some(account)),
+            Transaction::TransferTransaction { .. } => Some(counterparty),
+            _ => None,
+        };
+        let (owner, amount, currency, disputes) = match &mut transaction {
+            Transaction::WithdrawTransaction {
                 client,
                 amount,
-                state,
-            }) => {
-                ensure!(
-                    client == account.client(),
-                    "transaction references different client"
-                );
-                ensure!(
-                    state == expected_state,
-                    "disputed transaction is in a wrong state"
-                );
-                let new_transaction = Transaction::DepositTransaction {
+                currency,
+                disputes,
+            }
+            | Transaction::DepositTransaction {
+                client,
+                amount,
+                currency,
+                disputes,
+            }
+            | Transaction::TransferTransaction {
+                client,
+                amount,
+                currency,
+                disputes,
+                ..
+            } => (*client, *amount, currency.clone(), disputes),
+            Transaction::ConversionTransaction { client: owner, .. }
+            | Transaction::AdminTransaction { client: owner, .. }
+                if *owner != client =>
+            {
+                return Err(TxError::ClientMismatch {
                     client,
-                    amount,
-                    state: next_state,
-                };
-                Ok((amount, new_transaction))
+                    tx,
+                    owner: *owner,
+                })
+            }
+            Transaction::ConversionTransaction { .. } | Transaction::AdminTransaction { .. } => {
+                return Err(TxError::NotDisputable { client, tx })
+            }
+        };
+        if owner != client {
+            return Err(TxError::ClientMismatch { client, tx, owner });
+        }
+        if step == DisputeStep::Dispute && !disputable {
+            return Err(TxError::NotDisputable { client, tx });
+        }
+        match &command.currency {
+            Some(named) if *named != currency => {
+                return Err(TxError::CurrencyMismatch {
+                    client,
+                    tx,
+                    currency: named.clone(),
+                    expected: currency,
+                })
+            }
+            _ => (),
+        }
+
+        let remaining = disputes
+            .remaining(step, amount, redisputable)
+            .map_err(moneys_error)?;
+        if remaining == Moneys::ZERO {
+            return Err(TxError::WrongDisputeState { client, tx });
+        }
+        let moneys = match command.amount {
+            None => remaining,
+            Some(_) => self.moneys(command, &currency)?,
+        };
+        if moneys == Moneys::ZERO || remaining.less_than(moneys) {
+            return Err(TxError::InvalidDisputeAmount { client, tx });
+        }
+        let moneys = match (step, holder, self.policy.dispute_shortfall()) {
+            (DisputeStep::Dispute, Some(holder), DisputeShortfall::HoldAvailable) => {
+                let available = holder
+                    .and_then(|holder| holder.balance(&currency))
+                    .map_or(Moneys::ZERO, Balance::available);
+                match available {
+                    available if available == Moneys::ZERO => {
+                        return Err(TxError::InsufficientFunds {
+                            client: recipient.unwrap_or(client),
+                            tx,
+                        });
+                    }
+                    available if available.less_than(moneys) => available,
+                    _ => moneys,
+                }
             }
-            Some(_) => bail!("transaction is not deposit transaction"),
+            _ => moneys,
+        };
+        *disputes = disputes.step(step, moneys).map_err(moneys_error)?;
+
+        // Payer of a transfer is in the position of a client who made a withdrawal, the
+        // recipient in the position of a client who made a deposit
+        let (new_account, new_counterparty) = match (&transaction, recipient) {
+            (Transaction::DepositTransaction { .. }, _) => {
+                let new_account =
+                    step.apply_to_deposit(account, &currency, moneys, self.chargeback_restriction);
+                (new_account.map_err(moneys_error)?, None)
+            }
+            (Transaction::WithdrawTransaction { .. }, _) => {
+                let new_account = step.apply_to_withdrawal(account, &currency, moneys);
+                (new_account.map_err(moneys_error)?, None)
+            }
+            (Transaction::TransferTransaction { .. }, Some(to_client)) => {
+                let recipient = counterparty
+                    .cloned()
+                    .unwrap_or_else(|| Account::new(to_client));
+                self.check_allowed(&recipient, command.command_type, tx)?;
+                let new_account = step.apply_to_withdrawal(account, &currency, moneys);
+                let new_recipient = step
+                    .apply_to_deposit(&recipient, &currency, moneys, self.chargeback_restriction)
+                    .map_err(|error| TxError::from_moneys(error, to_client, tx))?;
+                (new_account.map_err(moneys_error)?, Some(new_recipient))
+            }
+            (Transaction::TransferTransaction { .. }, None)
+            | (Transaction::ConversionTransaction { .. }, _)
+            | (Transaction::AdminTransaction { .. }, _) => {
+                unreachable!(
+                    "transfers have a recipient, conversions and admin commands are refused above"
+                )
+            }
+        };
+        Ok(Change {
+            account: new_account,
+            counterparty: new_counterparty,
+            transaction,
+        })
+    }
+
+    /// Refuse command of `command_type` (with transaction `tx`) involving `account`, if it's
+    /// closed or some of its restrictions doesn't allow it. Chargeback lock is reported as
+    /// `LockedAccount`.
+    fn check_allowed(
+        &self,
+        account: &Account,
+        command_type: CommandType,
+        tx: TransactionId,
+    ) -> Result<()> {
+        let client = account.client();
+        if account.is_closed() {
+            return Err(TxError::ClosedAccount { client, tx });
         }
+        for (reason, restriction) in account.restrictions() {
+            if command_type.is_admin() || self.policy.allows(restriction, command_type) {
+                continue;
+            }
+            return Err(match reason {
+                RestrictionReason::Chargeback => TxError::LockedAccount { client, tx },
+                reason => TxError::RestrictedAccount {
+                    client,
+                    tx,
+                    restriction,
+                    reason,
+                },
+            });
+        }
+        Ok(())
     }
 
-    /// Applies command to given transaction and account, doesn't modify state
+    /// Checks that admin command can be applied to the account and computes its new state,
+    /// together with the record of the command. Closed account's available money is swept to
+    /// `recipient`, if the command names one.
+    fn administer(
+        &self,
+        command: &Command,
+        account: &Account,
+        recipient: Option<&Account>,
+    ) -> Result<Change> {
+        let (client, tx) = (command.client, command.tx);
+        let operator = command
+            .operator
+            .clone()
+            .filter(|operator| !operator.trim().is_empty())
+            .ok_or(TxError::MissingOperator { client, tx })?;
+        let mut new_account = account.clone();
+        let mut new_recipient = None;
+        let mut swept = BTreeMap::new();
+        let reason = command
+            .restriction_reason
+            .unwrap_or(RestrictionReason::Manual);
+        let mut restriction = None;
+        let action = match command.command_type {
+            CommandType::Unlock => {
+                restriction = new_account.lift(RestrictionReason::Chargeback);
+                if restriction.is_none() {
+                    return Err(TxError::NotLocked { client, tx });
+                }
+                AdminAction::Unlock
+            }
+            CommandType::Freeze => {
+                let new_restriction = command.restriction.unwrap_or(Restriction::Frozen);
+                if account.restriction(reason) == Some(new_restriction) {
+                    return Err(TxError::AlreadyFrozen { client, tx });
+                }
+                new_account.restrict(reason, new_restriction);
+                restriction = Some(new_restriction);
+                AdminAction::Freeze
+            }
+            CommandType::Unfreeze => {
+                restriction = new_account.lift(reason);
+                if restriction.is_none() {
+                    return Err(TxError::NotFrozen { client, tx });
+                }
+                AdminAction::Unfreeze
+            }
+            CommandType::Close => {
+                // Open disputes would have nowhere to go
+                let held = account
+                    .balances()
+                    .any(|(_, balance)| balance.held() != Moneys::ZERO);
+                let available = account
+                    .balances()
+                    .any(|(_, balance)| balance.available() != Moneys::ZERO);
+                if held || available && command.to_client.is_none() {
+                    return Err(TxError::NonZeroBalance { client, tx });
+                }
+                if let Some(to_client) = command.to_client {
+                    if to_client == client {
+                        return Err(TxError::SelfTransfer { client, tx });
+                    }
+                    let mut recipient = recipient
+                        .cloned()
+                        .unwrap_or_else(|| Account::new(to_client));
+                    self.check_allowed(&recipient, CommandType::Deposit, tx)?;
+                    for (currency, balance) in account.balances() {
+                        let moneys = balance.available();
+                        if moneys == Moneys::ZERO {
+                            continue;
+                        }
+                        new_account = new_account
+                            .withdraw(currency, moneys)
+                            .map_err(|error| TxError::from_moneys(error, client, tx))?;
+                        recipient = recipient
+                            .deposit(currency, moneys)
+                            .map_err(|error| TxError::from_moneys(error, to_client, tx))?;
+                        swept.insert(currency.clone(), moneys);
+                    }
+                    new_recipient = Some(recipient);
+                }
+                new_account.close();
+                AdminAction::Close
+            }
+            command_type => unreachable!("{:?} isn't an admin command", command_type),
+        };
+        Ok(Change {
+            account: new_account,
+            transaction: Transaction::AdminTransaction {
+                client,
+                action,
+                operator,
+                reason: command.reason.clone(),
+                to_client: new_recipient.as_ref().map(Account::client),
+                swept,
+                restriction_reason: match action {
+                    AdminAction::Unlock => Some(RestrictionReason::Chargeback),
+                    AdminAction::Freeze | AdminAction::Unfreeze => Some(reason),
+                    AdminAction::Close => None,
+                },
+                restriction,
+            },
+            counterparty: new_recipient,
+        })
+    }
+
+    /// Applies command to given transaction and account, doesn't modify state. `account` is
+    /// `None` if the client has no account yet, `counterparty` is the account of the other
+    /// client of a transfer, if it exists.
     fn apply_command(
+        &self,
         command: &Command,
-        account: Account,
+        account: Option<Account>,
+        counterparty: Option<&Account>,
         transaction: Option<Transaction>,
-    ) -> Result<(Account, Transaction)> {
-        ensure!(!account.is_locked(), "locked account");
+    ) -> Result<Change> {
+        let (client, tx) = (command.client, command.tx);
+        let exists = account.is_some();
+        let account = account.unwrap_or_else(|| Account::new(client));
+        self.check_allowed(&account, command.command_type, tx)?;
+        let moneys_error = |error| TxError::from_moneys(error, client, tx);
 
-        let r = match command.command_type {
+        let change = match command.command_type {
             CommandType::Withdrawal => {
-                ensure!(transaction.is_none(), "transaction already exists");
-                let moneys = command.get_moneys()?;
-                let new_account = account.withdraw(moneys)?;
+                if transaction.is_some() {
+                    return Err(TxError::DuplicateTxId { client, tx });
+                }
+                let currency = command.currency.clone().unwrap_or_default();
+                let moneys = self.moneys(command, &currency)?;
+                let new_account = account.withdraw(&currency, moneys).map_err(moneys_error)?;
                 let new_transaction = Transaction::WithdrawTransaction {
                     client: account.client(),
                     amount: moneys,
+                    currency,
+                    disputes: DisputedAmounts::NONE,
                 };
-                (new_account, new_transaction)
+                Change {
+                    account: new_account,
+                    counterparty: None,
+                    transaction: new_transaction,
+                }
             }
             CommandType::Deposit => {
-                ensure!(transaction.is_none(), "transaction already exists");
-                let moneys = command.get_moneys()?;
-                let new_account = account.deposit(moneys)?;
+                if transaction.is_some() {
+                    return Err(TxError::DuplicateTxId { client, tx });
+                }
+                let currency = command.currency.clone().unwrap_or_default();
+                let moneys = self.moneys(command, &currency)?;
+                let new_account = account.deposit(&currency, moneys).map_err(moneys_error)?;
                 let new_transaction = Transaction::DepositTransaction {
                     client: account.client(),
                     amount: moneys,
-                    state: DepositTransactionState::Deposited,
+                    currency,
+                    disputes: DisputedAmounts::NONE,
                 };
-                (new_account, new_transaction)
-            }
-            CommandType::Dispute => {
-                let (moneys, new_transaction) = Self::dispute_step(
-                    &account,
-                    transaction,
-                    DepositTransactionState::Deposited,
-                    DepositTransactionState::Disputed,
-                )?;
-                let new_account = account.dispute(moneys)?;
-                (new_account, new_transaction)
-            }
-            CommandType::Resolve => {
-                let (moneys, new_transaction) = Self::dispute_step(
-                    &account,
-                    transaction,
-                    DepositTransactionState::Disputed,
-                    DepositTransactionState::Deposited,
-                )?;
-                let new_account = account.resolve(moneys)?;
-                (new_account, new_transaction)
-            }
-            CommandType::Chargeback => {
-                let (moneys, new_transaction) = Self::dispute_step(
-                    &account,
-                    transaction,
-                    DepositTransactionState::Disputed,
-                    DepositTransactionState::ChargedBack,
-                )?;
-                let new_account = account.chargeback(moneys)?;
-                (new_account, new_transaction)
-            }
-        };
-        Ok(r)
-    }
-
-    #[allow(dead_code)]
-    pub fn unlock(&mut self, client: ClientId) -> Result<()> {
-        let account = self
-            .accounts
-            .get_mut(&client)
-            .ok_or(anyhow!("client not found"))?;
-        ensure!(account.is_locked(), "account not locked");
-        account.unlock();
+                Change {
+                    account: new_account,
+                    counterparty: None,
+                    transaction: new_transaction,
+                }
+            }
+            CommandType::Convert => {
+                if transaction.is_some() {
+                    return Err(TxError::DuplicateTxId { client, tx });
+                }
+                self.convert(command, &account)?
+            }
+            CommandType::Transfer => {
+                if transaction.is_some() {
+                    return Err(TxError::DuplicateTxId { client, tx });
+                }
+                self.transfer(command, &account, counterparty)?
+            }
+            CommandType::Dispute => self.dispute_step(
+                command,
+                DisputeStep::Dispute,
+                &account,
+                counterparty,
+                transaction,
+            )?,
+            CommandType::Resolve => self.dispute_step(
+                command,
+                DisputeStep::Resolve,
+                &account,
+                counterparty,
+                transaction,
+            )?,
+            CommandType::Chargeback => self.dispute_step(
+                command,
+                DisputeStep::Chargeback,
+                &account,
+                counterparty,
+                transaction,
+            )?,
+            CommandType::Unlock
+            | CommandType::Freeze
+            | CommandType::Unfreeze
+            | CommandType::Close => {
+                if transaction.is_some() {
+                    return Err(TxError::DuplicateTxId { client, tx });
+                }
+                if !exists {
+                    return Err(TxError::UnknownClient { client, tx });
+                }
+                self.administer(command, &account, counterparty)?
+            }
+        };
+
+        let touched = std::iter::once((Some(&account), &change.account))
+            .chain(change.counterparty.as_ref().map(|new| (counterparty, new)));
+        for (old, new) in touched {
+            let moneys_error = |error| TxError::from_moneys(error, new.client(), tx);
+            for (currency, balance) in new.balances() {
+                let total = old
+                    .map_or(Ok(Moneys::ZERO), |old| old.total(currency))
+                    .map_err(moneys_error)?;
+                let new_total = balance.total().map_err(moneys_error)?;
+                if total.less_than(new_total) && self.max_balance.less_than(new_total) {
+                    return Err(TxError::Overflow {
+                        client: new.client(),
+                        tx,
+                    });
+                }
+            }
+        }
+        Ok(change)
+    }
+
+    /// Save all accounts and transactions, so that processing can continue later in a
+    /// different process
+    pub fn snapshot<W: std::io::Write>(&mut self, raw_writer: W) -> anyhow::Result<()> {
+        let mut writer = std::io::BufWriter::new(raw_writer);
+        let mut write_record = |record: &snapshot::Record| -> std::io::Result<()> {
+            serde_json::to_writer(&mut writer, record)?;
+            writer.write_all(b"\n")
+        };
+        write_record(&snapshot::Record::Header {
+            version: snapshot::VERSION,
+        })?;
+        for account in self.accounts.values() {
+            write_record(&snapshot::Record::Account(account.clone()))?;
+        }
+        self.transactions.for_each(&mut |tx, transaction| {
+            write_record(&snapshot::Record::Transaction { tx, transaction })
+        })?;
+        writer.flush()?;
         Ok(())
     }
 
+    /// Load state saved by `snapshot`. Meant to be called on a freshly built processor, accounts
+    /// are replaced and transactions are added to the configured store.
+    pub fn restore<R: std::io::Read>(&mut self, raw_reader: R) -> anyhow::Result<()> {
+        let mut lines = std::io::BufReader::new(raw_reader).lines();
+        let header = lines.next().context("snapshot is empty")??;
+        match serde_json::from_str(&header).context("invalid snapshot header")? {
+            snapshot::Record::Header {
+                version: snapshot::VERSION,
+            } => (),
+            snapshot::Record::Header { version } => {
+                bail!("unsupported snapshot version {}", version)
+            }
+            _ => bail!("snapshot doesn't start with a header"),
+        }
+
+        let mut accounts = BTreeMap::new();
+        for (i, line) in lines.enumerate() {
+            let line = line?;
+            let record = serde_json::from_str(&line)
+                .with_context(|| format!("invalid snapshot record on line {}", i + 2))?;
+            match record {
+                snapshot::Record::Header { .. } => bail!("duplicate snapshot header"),
+                snapshot::Record::Account(account) => {
+                    accounts.insert(account.client(), account);
+                }
+                snapshot::Record::Transaction { tx, transaction } => {
+                    self.transactions.insert(tx, transaction)?;
+                }
+            }
+        }
+        self.accounts = accounts;
+        Ok(())
+    }
+
+    /// Rebuild state from records in the journal and report rejected rows recorded there to
+    /// `rejects`, returns input offset of the last row recorded there (if any), so that
+    /// processing can resume after it. Meant to be called on a freshly built processor (or
+    /// right after `restore` of the snapshot the journal continues from).
+    pub fn replay_journal(
+        &mut self,
+        mut rejects: Option<&mut dyn RejectSink>,
+    ) -> anyhow::Result<Option<u64>> {
+        let mut journal = match self.journal.take() {
+            None => return Ok(None),
+            Some(journal) => journal,
+        };
+        let mut last_offset = None;
+        let result = journal.for_each(|replayed| {
+            let entry = match replayed {
+                journal::Replayed::Entry(entry) => entry,
+                journal::Replayed::Rejected(rejected) => {
+                    if let Some(rejects) = rejects.as_mut() {
+                        rejects.reject(&rejected.rejection)?;
+                    }
+                    last_offset = Some(rejected.offset);
+                    return Ok(());
+                }
+            };
+            let tx = entry.tx;
+            if self.transactions.get(tx)?.is_some() {
+                self.transactions.update(tx, entry.transaction)?;
+            } else {
+                self.transactions.insert(tx, entry.transaction)?;
+            }
+            for account in std::iter::once(entry.account).chain(entry.counterparty) {
+                self.accounts.insert(account.client(), account);
+            }
+            last_offset = entry.offset.or(last_offset);
+            Ok(())
+        });
+        self.journal = Some(journal);
+        result.map(|_| last_offset)
+    }
+
+    /// Record rows rejected together (a single command or a whole batch) in the journal, with
+    /// their offsets in the input
+    pub(crate) fn journal_rejections(
+        &mut self,
+        rejections: &[(u64, Rejection)],
+    ) -> std::io::Result<()> {
+        match &mut self.journal {
+            Some(journal) if !rejections.is_empty() => journal.append(&journal::Record::Rejected(
+                rejections
+                    .iter()
+                    .map(|(offset, rejection)| journal::Rejected {
+                        offset: *offset,
+                        rejection: rejection.clone(),
+                    })
+                    .collect(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove account of `client`, so that a processor handling other clients can execute a
+    /// transfer involving it (see `put_account`)
+    pub(crate) fn take_account(&mut self, client: ClientId) -> Option<Account> {
+        self.accounts.remove(&client)
+    }
+
+    pub(crate) fn put_account(&mut self, account: Account) {
+        self.accounts.insert(account.client(), account);
+    }
+
+    /// Replace transaction kept by this processor with the one changed by a different processor
+    /// (see `execute_rows`)
+    pub(crate) fn put_transaction(
+        &mut self,
+        tx: TransactionId,
+        transaction: Transaction,
+    ) -> std::io::Result<()> {
+        self.transactions.update(tx, transaction)
+    }
+
     pub fn execute(&mut self, command: &Command) -> Result<()> {
-        let account = self
+        self.execute_at(command, None)
+    }
+
+    /// Lift the lock caused by a chargeback, recorded as admin transaction `tx` of `operator`.
+    /// Admin commands with a free-text reason can be run through `execute`.
+    pub fn unlock(&mut self, client: ClientId, tx: TransactionId, operator: &str) -> Result<()> {
+        self.execute(&Command::new(CommandType::Unlock, client, tx).operator(operator))
+    }
+
+    /// Restrict account with `restriction` for `reason`, recorded as admin transaction `tx`
+    pub fn freeze(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+        operator: &str,
+        restriction: Restriction,
+        reason: RestrictionReason,
+    ) -> Result<()> {
+        self.execute(
+            &Command::new(CommandType::Freeze, client, tx)
+                .operator(operator)
+                .restriction(restriction)
+                .restriction_reason(reason),
+        )
+    }
+
+    /// Lift restriction set for `reason`, recorded as admin transaction `tx`
+    pub fn unfreeze(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+        operator: &str,
+        reason: RestrictionReason,
+    ) -> Result<()> {
+        self.execute(
+            &Command::new(CommandType::Unfreeze, client, tx)
+                .operator(operator)
+                .restriction_reason(reason),
+        )
+    }
+
+    /// Close account, sweeping its available money to `to_client` if given, recorded as admin
+    /// transaction `tx`
+    pub fn close(
+        &mut self,
+        client: ClientId,
+        tx: TransactionId,
+        operator: &str,
+        to_client: Option<ClientId>,
+    ) -> Result<()> {
+        let command = Command::new(CommandType::Close, client, tx).operator(operator);
+        self.execute(&Command {
+            to_client,
+            ..command
+        })
+    }
+
+    /// Execute command read from given `offset` of the input, the offset is recorded in the
+    /// journal
+    pub fn execute_at(&mut self, command: &Command, offset: Option<u64>) -> Result<()> {
+        let mut staging = Staging::default();
+        self.stage(&mut staging, command, offset, &HashMap::new())?;
+        self.commit(staging, &mut HashMap::new())
+            .map_err(|error| storage_error(command, error))
+    }
+
+    /// Execute all `commands` or none of them. Every command sees the changes made by the ones
+    /// before it, like if they were executed one by one. The `batch` field of the commands is
+    /// ignored.
+    pub fn execute_batch(&mut self, commands: &[Command]) -> Result<(), BatchError> {
+        let commands: Vec<_> = commands.iter().map(|command| (command, None)).collect();
+        self.execute_batch_at(&commands, &mut HashMap::new())
+    }
+
+    /// Execute rows of a batch read from the input, returning result of every row. When one
+    /// row is rejected, the others fail with `TxError::BatchAborted`.
+    ///
+    /// `borrowed` are transactions kept by a different processor (when clients are split among
+    /// several). Rows use them like transactions of the store, but their changes are written
+    /// back to `borrowed` rather than to the store. The journal records them like the others,
+    /// so it can't be replayed by this processor alone.
+    pub(crate) fn execute_rows(
+        &mut self,
+        rows: &[Row],
+        borrowed: &mut HashMap<TransactionId, Transaction>,
+    ) -> Vec<Result<()>> {
+        let commands: Vec<_> = rows
+            .iter()
+            .map(|row| (&row.command, Some(row.offset)))
+            .collect();
+        match self.execute_batch_at(&commands, borrowed) {
+            Ok(()) => vec![Ok(()); rows.len()],
+            Err(BatchError { index, error }) => rows
+                .iter()
+                .enumerate()
+                .map(|(i, row)| {
+                    Err(if i == index {
+                        error.clone()
+                    } else {
+                        TxError::BatchAborted {
+                            client: row.command.client,
+                            tx: row.command.tx,
+                        }
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// Execute batch of commands read from given offsets of the input
+    fn execute_batch_at(
+        &mut self,
+        commands: &[(&Command, Option<u64>)],
+        borrowed: &mut HashMap<TransactionId, Transaction>,
+    ) -> Result<(), BatchError> {
+        let mut staging = Staging::default();
+        for (index, (command, offset)) in commands.iter().enumerate() {
+            self.stage(&mut staging, command, *offset, borrowed)
+                .map_err(|error| BatchError { index, error })?;
+        }
+        match commands.first() {
+            None => Ok(()),
+            Some((command, _)) => self.commit(staging, borrowed).map_err(|error| BatchError {
+                index: 0,
+                error: storage_error(command, error),
+            }),
+        }
+    }
+
+    /// Account of `client` as changed by `staging`
+    fn staged_account<'a>(&'a self, staging: &'a Staging, client: ClientId) -> Option<&'a Account> {
+        staging
             .accounts
-            .get(&command.client)
-            .map(|account| (*account).clone())
-            .unwrap_or_else(|| Account::new(command.client));
-        let transaction = self
-            .transactions
-            .get(&command.tx)
-            .map(|transaction| transaction.to_owned());
+            .get(&client)
+            .or_else(|| self.accounts.get(&client))
+    }
 
-        let (new_account, new_transaction) = Self::apply_command(command, account, transaction)?;
+    /// Apply `command` to the state changed by `staging` and add its changes there. Transactions
+    /// of `borrowed` are used like the ones in the store (see `execute_rows`).
+    fn stage(
+        &mut self,
+        staging: &mut Staging,
+        command: &Command,
+        offset: Option<u64>,
+        borrowed: &HashMap<TransactionId, Transaction>,
+    ) -> Result<()> {
+        let (client, tx) = (command.client, command.tx);
+        let (transaction, exists) = match staging.transactions.get(&tx) {
+            Some((transaction, exists)) => (Some(transaction.clone()), *exists),
+            None if borrowed.contains_key(&tx) => (borrowed.get(&tx).cloned(), true),
+            None => {
+                let transaction = self
+                    .transactions
+                    .get(tx)
+                    .map_err(|error| storage_error(command, error))?;
+                let exists = transaction.is_some();
+                (transaction, exists)
+            }
+        };
+        let account = self.staged_account(staging, client).cloned();
+        let counterparty = counterparty(command, transaction.as_ref())
+            .and_then(|counterparty| self.staged_account(staging, counterparty));
+
+        let change = self.apply_command(command, account, counterparty, transaction)?;
 
-        self.accounts.insert(command.client, new_account);
-        self.transactions.insert(command.tx, new_transaction);
+        staging.entries.push(journal::Entry {
+            offset,
+            account: change.account.clone(),
+            counterparty: change.counterparty.clone(),
+            tx,
+            transaction: change.transaction.clone(),
+        });
+        staging
+            .transactions
+            .insert(tx, (change.transaction, exists));
+        staging.accounts.insert(client, change.account);
+        if let Some(counterparty) = change.counterparty {
+            staging.accounts.insert(counterparty.client(), counterparty);
+        }
+        Ok(())
+    }
 
+    /// Record staged changes in the journal and apply them, changes of `borrowed` transactions
+    /// are written back there
+    fn commit(
+        &mut self,
+        mut staging: Staging,
+        borrowed: &mut HashMap<TransactionId, Transaction>,
+    ) -> std::io::Result<()> {
+        if let Some(journal) = &mut self.journal {
+            let record = match staging.entries.len() {
+                1 => journal::Record::Entry(staging.entries.remove(0)),
+                _ => journal::Record::Batch(staging.entries),
+            };
+            journal.append(&record)?;
+        }
+        for (tx, (transaction, exists)) in staging.transactions {
+            if let Some(borrowed) = borrowed.get_mut(&tx) {
+                *borrowed = transaction;
+            } else if exists {
+                self.transactions.update(tx, transaction)?;
+            } else {
+                self.transactions.insert(tx, transaction)?;
+            }
+        }
+        self.accounts.extend(staging.accounts);
         Ok(())
     }
 }
+
+/// Error of a command that couldn't access the transaction store or the journal
+fn storage_error(command: &Command, error: std::io::Error) -> TxError {
+    TxError::Storage {
+        client: command.client,
+        tx: command.tx,
+        message: error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::policy::{LenientPolicy, StrictPolicy};
+    use crate::restriction::RestrictionPolicy;
+
+    fn admin(command_type: CommandType, client: ClientId, tx: TransactionId) -> Command {
+        Command::new(command_type, client, tx)
+            .operator("alice")
+            .reason("investigation")
+    }
+
+    fn deposit(client: ClientId, tx: TransactionId, amount: &str) -> Command {
+        Command::new(CommandType::Deposit, client, tx).amount(amount)
+    }
+
+    fn withdrawal(client: ClientId, tx: TransactionId, amount: &str) -> Command {
+        Command::new(CommandType::Withdrawal, client, tx).amount(amount)
+    }
+
+    #[test]
+    fn errors() {
+        let mut processor = Processor::default();
+        processor.execute(&deposit(1, 10, "5.0")).unwrap();
+
+        assert_eq!(
+            processor.execute(&deposit(2, 10, "1.0")),
+            Err(TxError::DuplicateTxId { client: 2, tx: 10 })
+        );
+        assert_eq!(
+            processor.execute(&Command::new(CommandType::Deposit, 1, 11)),
+            Err(TxError::MissingAmount { client: 1, tx: 11 })
+        );
+        assert!(matches!(
+            processor.execute(&deposit(1, 11, "abc")),
+            Err(TxError::InvalidAmount {
+                client: 1,
+                tx: 11,
+                ..
+            })
+        ));
+        assert_eq!(
+            processor.execute(&withdrawal(1, 11, "6.0")),
+            Err(TxError::InsufficientFunds { client: 1, tx: 11 })
+        );
+        assert_eq!(
+            processor.execute(&Command::new(CommandType::Dispute, 1, 12)),
+            Err(TxError::UnknownTx { client: 1, tx: 12 })
+        );
+        assert_eq!(
+            processor.execute(&Command::new(CommandType::Dispute, 2, 10)),
+            Err(TxError::ClientMismatch {
+                client: 2,
+                tx: 10,
+                owner: 1
+            })
+        );
+        assert_eq!(
+            processor.execute(&Command::new(CommandType::Resolve, 1, 10)),
+            Err(TxError::WrongDisputeState { client: 1, tx: 10 })
+        );
+
+        processor
+            .execute(&Command::new(CommandType::Dispute, 1, 10))
+            .unwrap();
+        processor
+            .execute(&Command::new(CommandType::Chargeback, 1, 10))
+            .unwrap();
+        assert_eq!(
+            processor.execute(&deposit(1, 13, "1.0")),
+            Err(TxError::LockedAccount { client: 1, tx: 13 })
+        );
+
+        assert_eq!(
+            processor.unlock(3, 14, "alice"),
+            Err(TxError::UnknownClient { client: 3, tx: 14 })
+        );
+        assert_eq!(
+            processor.execute(&Command::new(CommandType::Unlock, 1, 14)),
+            Err(TxError::MissingOperator { client: 1, tx: 14 })
+        );
+        assert_eq!(
+            processor.unlock(1, 14, " "),
+            Err(TxError::MissingOperator { client: 1, tx: 14 })
+        );
+        assert_eq!(processor.unlock(1, 14, "alice"), Ok(()));
+        assert_eq!(
+            processor.unlock(1, 15, "alice"),
+            Err(TxError::NotLocked { client: 1, tx: 15 })
+        );
+        processor.execute(&deposit(1, 13, "1.0")).unwrap();
+    }
+
+    #[test]
+    fn admin_commands() {
+        let mut processor = Processor::default();
+        processor.execute(&deposit(1, 1, "5.0")).unwrap();
+        processor.execute(&deposit(2, 2, "1.0")).unwrap();
+        let transfer = |tx| {
+            Command::new(CommandType::Transfer, 2, tx)
+                .amount("1.0")
+                .to_client(1)
+        };
+
+        processor
+            .execute(&admin(CommandType::Freeze, 1, 3))
+            .unwrap();
+        assert_eq!(
+            processor.execute(&admin(CommandType::Freeze, 1, 4)),
+            Err(TxError::AlreadyFrozen { client: 1, tx: 4 })
+        );
+        assert_eq!(
+            processor.execute(&deposit(1, 4, "1.0")),
+            Err(TxError::RestrictedAccount {
+                client: 1,
+                tx: 4,
+                restriction: Restriction::Frozen,
+                reason: RestrictionReason::Manual
+            })
+        );
+        assert_eq!(
+            processor.execute(&transfer(4)),
+            Err(TxError::RestrictedAccount {
+                client: 1,
+                tx: 4,
+                restriction: Restriction::Frozen,
+                reason: RestrictionReason::Manual
+            })
+        );
+        assert_eq!(
+            processor.execute(&Command::new(CommandType::Dispute, 1, 3)),
+            Err(TxError::RestrictedAccount {
+                client: 1,
+                tx: 3,
+                restriction: Restriction::Frozen,
+                reason: RestrictionReason::Manual
+            })
+        );
+        processor
+            .unfreeze(1, 4, "alice", RestrictionReason::Manual)
+            .unwrap();
+        assert_eq!(
+            processor.execute(&admin(CommandType::Unfreeze, 1, 5)),
+            Err(TxError::NotFrozen { client: 1, tx: 5 })
+        );
+        // Admin commands are recorded, but can't be disputed
+        assert_eq!(
+            processor.execute(&Command::new(CommandType::Dispute, 1, 3)),
+            Err(TxError::NotDisputable { client: 1, tx: 3 })
+        );
+        assert!(matches!(
+            processor.transaction(4).unwrap(),
+            Some(Transaction::AdminTransaction {
+                client: 1,
+                action: AdminAction::Unfreeze,
+                ref operator,
+                ..
+            }) if operator == "alice"
+        ));
+
+        // Only empty account can be closed, unless its money is swept elsewhere
+        assert_eq!(
+            processor.execute(&admin(CommandType::Close, 1, 5)),
+            Err(TxError::NonZeroBalance { client: 1, tx: 5 })
+        );
+        processor.close(1, 5, "alice", Some(3)).unwrap();
+        let total = |client| {
+            processor
+                .account(client)
+                .unwrap()
+                .total(&Currency::default())
+                .unwrap()
+        };
+        assert_eq!(total(1), Moneys::ZERO);
+        assert_eq!(total(3), Moneys::new(50_000));
+        assert_eq!(
+            processor.execute(&transfer(6)),
+            Err(TxError::ClosedAccount { client: 1, tx: 6 })
+        );
+        assert_eq!(
+            processor.execute(&admin(CommandType::Freeze, 1, 6)),
+            Err(TxError::ClosedAccount { client: 1, tx: 6 })
+        );
+    }
+
+    #[test]
+    fn restrictions() {
+        let mut processor = Processor::builder()
+            .chargeback_restriction(Restriction::DepositsOnly)
+            .build();
+        processor.execute(&deposit(1, 1, "5.0")).unwrap();
+        processor.execute(&deposit(1, 2, "1.0")).unwrap();
+        processor.execute(&deposit(1, 3, "1.0")).unwrap();
+        for tx in [1, 2] {
+            processor
+                .execute(&Command::new(CommandType::Dispute, 1, tx))
+                .unwrap();
+        }
+
+        // Other disputes can still be settled after the account got locked
+        processor
+            .execute(&Command::new(CommandType::Chargeback, 1, 1))
+            .unwrap();
+        assert_eq!(
+            processor.execute(&Command::new(CommandType::Dispute, 1, 3)),
+            Err(TxError::LockedAccount { client: 1, tx: 3 })
+        );
+        processor
+            .execute(&Command::new(CommandType::Resolve, 1, 2))
+            .unwrap();
+        processor.execute(&deposit(1, 4, "1.0")).unwrap();
+        assert_eq!(
+            processor.execute(&withdrawal(1, 5, "1.0")),
+            Err(TxError::LockedAccount { client: 1, tx: 5 })
+        );
+
+        // Every restriction has to allow the command
+        processor
+            .freeze(
+                1,
+                5,
+                "alice",
+                Restriction::Frozen,
+                RestrictionReason::Compliance,
+            )
+            .unwrap();
+        assert_eq!(
+            processor.execute(&deposit(1, 6, "1.0")),
+            Err(TxError::RestrictedAccount {
+                client: 1,
+                tx: 6,
+                restriction: Restriction::Frozen,
+                reason: RestrictionReason::Compliance
+            })
+        );
+        // Restriction for the same reason is replaced
+        processor
+            .freeze(
+                1,
+                6,
+                "alice",
+                Restriction::DepositsOnly,
+                RestrictionReason::Compliance,
+            )
+            .unwrap();
+        processor.execute(&deposit(1, 7, "1.0")).unwrap();
+        assert_eq!(
+            processor.execute(&admin(CommandType::Unfreeze, 1, 8)),
+            Err(TxError::NotFrozen { client: 1, tx: 8 })
+        );
+        processor
+            .execute(
+                &admin(CommandType::Unfreeze, 1, 8)
+                    .restriction_reason(RestrictionReason::Compliance),
+            )
+            .unwrap();
+        assert!(matches!(
+            processor.transaction(8).unwrap(),
+            Some(Transaction::AdminTransaction {
+                restriction: Some(Restriction::DepositsOnly),
+                restriction_reason: Some(RestrictionReason::Compliance),
+                ..
+            })
+        ));
+        processor
+            .execute(&admin(CommandType::Unlock, 1, 9))
+            .unwrap();
+        processor.execute(&withdrawal(1, 10, "1.0")).unwrap();
+
+        // Policy can be stricter than the default
+        let mut processor = Processor::builder()
+            .policy(Box::new(StandardPolicy::new(
+                RestrictionPolicy::default().allow(Restriction::Frozen, &[]),
+            )))
+            .build();
+        processor.execute(&deposit(1, 1, "5.0")).unwrap();
+        processor.execute(&deposit(1, 2, "1.0")).unwrap();
+        for tx in [1, 2] {
+            processor
+                .execute(&Command::new(CommandType::Dispute, 1, tx))
+                .unwrap();
+        }
+        processor
+            .execute(&Command::new(CommandType::Chargeback, 1, 1))
+            .unwrap();
+        assert_eq!(
+            processor.execute(&Command::new(CommandType::Resolve, 1, 2)),
+            Err(TxError::LockedAccount { client: 1, tx: 2 })
+        );
+    }
+
+    #[test]
+    fn policies() {
+        let dispute = |tx, amount: Option<&str>| Command {
+            amount: amount.map(str::to_owned),
+            ..Command::new(CommandType::Dispute, 1, tx)
+        };
+
+        // Only deposits can be disputed, and only once
+        let mut processor = Processor::builder()
+            .policy(Box::new(StrictPolicy::default()))
+            .build();
+        processor.execute(&deposit(1, 1, "5.0")).unwrap();
+        processor.execute(&withdrawal(1, 2, "1.0")).unwrap();
+        assert_eq!(
+            processor.execute(&dispute(2, None)),
+            Err(TxError::NotDisputable { client: 1, tx: 2 })
+        );
+        processor.execute(&dispute(1, Some("2.0"))).unwrap();
+        processor
+            .execute(&Command::new(CommandType::Resolve, 1, 1))
+            .unwrap();
+        assert_eq!(
+            processor.execute(&dispute(1, Some("3.5"))),
+            Err(TxError::InvalidDisputeAmount { client: 1, tx: 1 })
+        );
+        processor.execute(&dispute(1, Some("2.0"))).unwrap();
+        processor
+            .execute(&Command::new(CommandType::Chargeback, 1, 1))
+            .unwrap();
+        // Restricted account accepts nothing but admin commands
+        assert_eq!(
+            processor.execute(&deposit(1, 3, "1.0")),
+            Err(TxError::LockedAccount { client: 1, tx: 3 })
+        );
+        processor
+            .execute(&admin(CommandType::Unlock, 1, 3))
+            .unwrap();
+        assert_eq!(
+            processor.execute(&dispute(1, Some("1.5"))),
+            Err(TxError::InvalidDisputeAmount { client: 1, tx: 1 })
+        );
+
+        // Dispute of spent money holds what's left
+        let mut processor = Processor::builder()
+            .policy(Box::new(LenientPolicy::default()))
+            .build();
+        processor.execute(&deposit(1, 1, "5.0")).unwrap();
+        processor.execute(&withdrawal(1, 2, "4.0")).unwrap();
+        processor.execute(&dispute(1, None)).unwrap();
+        let balance = processor
+            .account(1)
+            .unwrap()
+            .balance(&Currency::default())
+            .copied()
+            .unwrap();
+        assert_eq!(balance.available(), Moneys::ZERO);
+        assert_eq!(balance.held(), Moneys::new(10_000));
+        assert_eq!(
+            processor.execute(&dispute(1, None)),
+            Err(TxError::InsufficientFunds { client: 1, tx: 1 })
+        );
+        processor.execute(&deposit(1, 3, "1.0")).unwrap();
+        processor.execute(&dispute(1, None)).unwrap();
+        processor
+            .execute(&Command::new(CommandType::Chargeback, 1, 1))
+            .unwrap();
+        assert_eq!(
+            processor.account(1).unwrap().total(&Currency::default()),
+            Ok(Moneys::ZERO)
+        );
+
+        // The same is refused by default
+        let mut processor = Processor::default();
+        processor.execute(&deposit(1, 1, "5.0")).unwrap();
+        processor.execute(&withdrawal(1, 2, "4.0")).unwrap();
+        assert_eq!(
+            processor.execute(&dispute(1, None)),
+            Err(TxError::InsufficientFunds { client: 1, tx: 1 })
+        );
+    }
+
+    #[test]
+    fn max_balance() {
+        let mut processor = Processor::builder()
+            .max_balance(Moneys::new(100_000))
+            .build();
+        processor.execute(&deposit(1, 1, "6.0")).unwrap();
+        assert_eq!(
+            processor.execute(&deposit(1, 2, "4.0001")),
+            Err(TxError::Overflow { client: 1, tx: 2 })
+        );
+        processor.execute(&deposit(1, 2, "4.0")).unwrap();
+        processor
+            .execute(&Command::new(CommandType::Dispute, 1, 2))
+            .unwrap();
+        processor
+            .execute(&Command::new(CommandType::Resolve, 1, 2))
+            .unwrap();
+
+        // Disputed withdrawal would raise the total above the limit
+        processor.execute(&withdrawal(1, 3, "1.0")).unwrap();
+        processor.execute(&deposit(1, 4, "1.0")).unwrap();
+        assert_eq!(
+            processor.execute(&Command::new(CommandType::Dispute, 1, 3)),
+            Err(TxError::Overflow { client: 1, tx: 3 })
+        );
+
+        // Balances far beyond u64 still work without the limit
+        let mut processor = Processor::default();
+        processor
+            .execute(&deposit(1, 1, "10000000000000000000000000"))
+            .unwrap();
+        processor
+            .execute(&deposit(1, 2, "10000000000000000000000000"))
+            .unwrap();
+        processor
+            .execute(&Command::new(CommandType::Dispute, 1, 1))
+            .unwrap();
+        processor
+            .execute(&Command::new(CommandType::Resolve, 1, 1))
+            .unwrap();
+        assert_eq!(
+            processor.account(1).unwrap().total(&Currency::default()),
+            "20000000000000000000000000".parse()
+        );
+    }
+
+    #[test]
+    fn batches() {
+        let mut processor = Processor::default();
+        processor.execute(&deposit(1, 10, "5.0")).unwrap();
+        let transfer = Command::new(CommandType::Transfer, 1, 11)
+            .amount("3.0")
+            .to_client(2);
+
+        // Later commands see changes of the earlier ones, but nothing is applied on rejection
+        assert_eq!(
+            processor.execute_batch(&[
+                transfer.clone(),
+                withdrawal(2, 12, "2.0"),
+                withdrawal(1, 13, "3.0"),
+            ]),
+            Err(BatchError {
+                index: 2,
+                error: TxError::InsufficientFunds { client: 1, tx: 13 }
+            })
+        );
+        assert_eq!(processor.accounts().len(), 1);
+        assert_eq!(
+            processor.execute(&Command::new(CommandType::Dispute, 1, 11)),
+            Err(TxError::UnknownTx { client: 1, tx: 11 })
+        );
+
+        processor
+            .execute_batch(&[
+                transfer,
+                withdrawal(2, 12, "2.0"),
+                deposit(1, 13, "1.0"),
+                Command::new(CommandType::Dispute, 1, 13),
+            ])
+            .unwrap();
+        let balance = |client| {
+            let account = processor.account(client).unwrap();
+            let balance = account.balance(&Currency::default()).unwrap();
+            (balance.available().to_string(), balance.held().to_string())
+        };
+        assert_eq!(balance(1), ("2.0000".to_owned(), "1.0000".to_owned()));
+        assert_eq!(balance(2), ("1.0000".to_owned(), "0.0000".to_owned()));
+        processor
+            .execute(&Command::new(CommandType::Resolve, 1, 13))
+            .unwrap();
+    }
+
+    #[test]
+    fn scale() {
+        let mut processor = Processor::builder().scale(2).build();
+        processor.execute(&deposit(1, 1, "1.005")).unwrap();
+        processor.execute(&deposit(1, 2, "0.015")).unwrap();
+        let account = processor.account(1).unwrap().clone();
+        assert_eq!(
+            account.total(&Currency::default()).unwrap().to_string(),
+            "1.02"
+        );
+
+        // Scale survives snapshots
+        let mut saved = vec![];
+        processor.snapshot(&mut saved).unwrap();
+        let mut restored = Processor::builder().scale(2).build();
+        restored.restore(saved.as_slice()).unwrap();
+        restored
+            .execute(&Command::new(CommandType::Dispute, 1, 2))
+            .unwrap();
+        let account = restored.account(1).unwrap().clone();
+        assert_eq!(account.rows().unwrap()[0].held.to_string(), "0.02");
+
+        let mut processor = Processor::builder()
+            .scale(0)
+            .rounding(Rounding::Reject)
+            .build();
+        processor.execute(&deposit(1, 1, "1500")).unwrap();
+        assert!(matches!(
+            processor.execute(&deposit(1, 2, "0.5")),
+            Err(TxError::InvalidAmount {
+                source: MoneysError::TooPrecise,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn currencies() {
+        let (usd, jpy) = (Currency::new("USD").unwrap(), Currency::new("JPY").unwrap());
+        let in_currency =
+            |command: Command, currency: &Currency| command.currency(currency.clone());
+        let mut processor = Processor::builder()
+            .currency_scale(usd.clone(), 2)
+            .currency_scale(jpy.clone(), 0)
+            .build();
+        processor
+            .execute(&in_currency(deposit(1, 1, "10.005"), &usd))
+            .unwrap();
+        processor
+            .execute(&in_currency(deposit(1, 2, "1500"), &jpy))
+            .unwrap();
+        processor.execute(&deposit(1, 3, "1.5")).unwrap();
+
+        // Money in one currency can't pay for another
+        assert_eq!(
+            processor.execute(&in_currency(withdrawal(1, 4, "11"), &usd)),
+            Err(TxError::InsufficientFunds { client: 1, tx: 4 })
+        );
+        assert_eq!(
+            processor.execute(&in_currency(Command::new(CommandType::Dispute, 1, 2), &usd)),
+            Err(TxError::CurrencyMismatch {
+                client: 1,
+                tx: 2,
+                currency: usd.clone(),
+                expected: jpy.clone(),
+            })
+        );
+        processor
+            .execute(&Command::new(CommandType::Dispute, 1, 2))
+            .unwrap();
+
+        let account = processor.account(1).unwrap();
+        assert_eq!(account.total(&usd).unwrap().to_string(), "10.00");
+        assert_eq!(account.balance(&jpy).unwrap().held().to_string(), "1500");
+        assert_eq!(
+            account.total(&Currency::default()).unwrap().to_string(),
+            "1.5000"
+        );
+    }
+
+    #[test]
+    fn conversions() {
+        let (eur, usd) = (Currency::new("EUR").unwrap(), Currency::new("USD").unwrap());
+        let rates =
+            RateTable::read(&b"pair,rate,valid_from\nEUR/USD,1.05,0\nEUR/USD,1.10,100\n"[..])
+                .unwrap();
+        let mut processor = Processor::builder()
+            .currency_scale(usd.clone(), 2)
+            .currency_scale(eur.clone(), 2)
+            .rates(rates)
+            .build();
+        let convert = |tx, amount: &str, from: &Currency, to: &Currency, time| Command {
+            time,
+            ..Command::new(CommandType::Convert, 1, tx)
+                .amount(amount)
+                .currency(from.clone())
+                .to_currency(to.clone())
+        };
+        processor
+            .execute(&deposit(1, 1, "100").currency(eur.clone()))
+            .unwrap();
+
+        processor
+            .execute(&convert(2, "10", &eur, &usd, Some(50)))
+            .unwrap();
+        processor
+            .execute(&convert(3, "10", &eur, &usd, None))
+            .unwrap();
+        // Inverse of the latest rate, 10 / 1.10 = 9.0909...
+        processor
+            .execute(&convert(4, "10", &usd, &eur, None))
+            .unwrap();
+        let account = processor.account(1).unwrap();
+        assert_eq!(account.total(&eur).unwrap().to_string(), "89.09");
+        assert_eq!(account.total(&usd).unwrap().to_string(), "11.50");
+
+        assert_eq!(
+            processor.execute(&convert(5, "100", &eur, &usd, None)),
+            Err(TxError::InsufficientFunds { client: 1, tx: 5 })
+        );
+        assert_eq!(
+            processor.execute(&convert(5, "1", &eur, &Currency::default(), None)),
+            Err(TxError::NoRate {
+                client: 1,
+                tx: 5,
+                from: eur.clone(),
+                to: Currency::default(),
+            })
+        );
+        assert_eq!(
+            processor.execute(&convert(2, "1", &eur, &usd, None)),
+            Err(TxError::DuplicateTxId { client: 1, tx: 2 })
+        );
+        assert_eq!(
+            processor.execute(&Command::new(CommandType::Dispute, 1, 2)),
+            Err(TxError::NotDisputable { client: 1, tx: 2 })
+        );
+
+        // The rate is kept for audit
+        match processor.transaction(2).unwrap() {
+            Some(Transaction::ConversionTransaction {
+                converted, rate, ..
+            }) => {
+                assert_eq!(converted.to_string(), "10.50");
+                assert_eq!(rate.valid_from, 0);
+            }
+            other => panic!("unexpected transaction {:?}", other),
+        }
+
+        // Rate announced for the future is used only when asked for the latest rates
+        let rates = "pair,rate,valid_from\nEUR/USD,1.05,0\nEUR/USD,2.00,18446744073709551615\n";
+        let mut processor = Processor::builder()
+            .currency_scale(usd.clone(), 2)
+            .currency_scale(eur.clone(), 2)
+            .rates(RateTable::read(rates.as_bytes()).unwrap())
+            .latest_rates()
+            .build();
+        processor
+            .execute(&deposit(1, 1, "100").currency(eur.clone()))
+            .unwrap();
+        processor
+            .execute(&convert(2, "10", &eur, &usd, None))
+            .unwrap();
+        let account = processor.account(1).unwrap();
+        assert_eq!(account.total(&usd).unwrap().to_string(), "20.00");
+    }
+
+    #[test]
+    fn snapshot() {
+        let mut processor = Processor::default();
+        processor.execute(&deposit(1, 10, "5.0")).unwrap();
+        processor.execute(&deposit(2, 20, "7.0")).unwrap();
+        processor
+            .execute(&Command::new(CommandType::Dispute, 2, 20))
+            .unwrap();
+
+        let mut saved = vec![];
+        processor.snapshot(&mut saved).unwrap();
+        let mut restored = Processor::default();
+        restored.restore(saved.as_slice()).unwrap();
+        assert_eq!(restored.accounts(), processor.accounts());
+
+        // Disputes against transactions from before the snapshot still work
+        for processor in [&mut processor, &mut restored].iter_mut() {
+            processor
+                .execute(&Command::new(CommandType::Dispute, 1, 10))
+                .unwrap();
+            processor
+                .execute(&Command::new(CommandType::Chargeback, 2, 20))
+                .unwrap();
+            assert_eq!(
+                processor.execute(&deposit(3, 10, "1.0")),
+                Err(TxError::DuplicateTxId { client: 3, tx: 10 })
+            );
+        }
+        assert_eq!(restored.accounts(), processor.accounts());
+
+        let mut restored = Processor::default();
+        assert!(restored
+            .restore(&b"{\"header\":{\"version\":999}}\n"[..])
+            .is_err());
+        // Accounts had a different format in the first version
+        assert!(restored
+            .restore(&b"{\"header\":{\"version\":1}}\n"[..])
+            .is_err());
+        assert!(restored.restore(&b""[..]).is_err());
+    }
+}
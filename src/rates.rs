@@ -0,0 +1,174 @@
+//! Exchange rates for currency conversions
+//!
+//! Rates are loaded from a CSV file with a header and columns `pair`, `rate` and `valid_from`,
+//! like `EUR/USD,1.0843,1700000000`, meaning one EUR buys 1.0843 USD from the given Unix time
+//! on. Rate of a pair applies in the opposite direction too, unless that direction has its own
+//! rate.
+
+use crate::currency::Currency;
+use crate::moneys::{Moneys, MoneysError, Rounding};
+use anyhow::{anyhow, bail, Context as _, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Rate quoted for a currency pair, as recorded with conversions for audit
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Rate {
+    pub base: Currency,
+    pub quote: Currency,
+    /// How many units of `quote` one unit of `base` buys
+    pub rate: Moneys,
+    /// Unix time since which the rate applies
+    pub valid_from: u64,
+}
+
+impl Rate {
+    /// Convert `amount` from `from` (either side of the pair) to the other currency, with
+    /// `scale` decimal places
+    pub fn convert(
+        &self,
+        from: &Currency,
+        amount: Moneys,
+        scale: u8,
+        rounding: Rounding,
+    ) -> Result<Moneys, MoneysError> {
+        let one = Moneys::with_scale(1, 0);
+        if *from == self.base {
+            amount.mul_div(self.rate, one, scale, rounding)
+        } else {
+            amount.mul_div(one, self.rate, scale, rounding)
+        }
+    }
+}
+
+/// Current Unix time, so that conversions without time use the newest rate already in effect
+/// rather than one that is only announced (unless `ProcessorBuilder::latest_rates` is set)
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+#[derive(Debug, Deserialize)]
+struct RateRow {
+    pair: String,
+    rate: String,
+    valid_from: u64,
+}
+
+/// Rates of currency pairs over time
+#[derive(Debug, Clone, Default)]
+pub struct RateTable {
+    /// Rates of every pair ordered by `valid_from`
+    rates: HashMap<(Currency, Currency), Vec<Rate>>,
+}
+
+impl RateTable {
+    /// Read rates file at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let f = std::fs::File::open(path)
+            .with_context(|| format!("failed opening rates file {:?}", path))?;
+        Self::read(f).with_context(|| format!("failed reading rates file {:?}", path))
+    }
+
+    /// Read rates in CSV with a header
+    pub fn read<R: std::io::Read>(raw_reader: R) -> Result<Self> {
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(raw_reader);
+        let mut table = Self::default();
+        for (i, row) in reader.deserialize::<RateRow>().enumerate() {
+            // Line 1 is the header
+            let line = i + 2;
+            let row = row?;
+            let (base, quote) = row
+                .pair
+                .split_once('/')
+                .ok_or_else(|| anyhow!("pair on line {} isn't like EUR/USD", line))?;
+            let rate: Moneys = row
+                .rate
+                .parse()
+                .map_err(|error| anyhow!("invalid rate on line {}: {}", line, error))?;
+            if rate == Moneys::ZERO {
+                bail!("zero rate on line {}", line);
+            }
+            let currency = |code| {
+                Currency::new(code)
+                    .map_err(|error| anyhow!("invalid pair on line {}: {}", line, error))
+            };
+            table.insert(Rate {
+                base: currency(base)?,
+                quote: currency(quote)?,
+                rate,
+                valid_from: row.valid_from,
+            });
+        }
+        Ok(table)
+    }
+
+    pub fn insert(&mut self, rate: Rate) {
+        let rates = self
+            .rates
+            .entry((rate.base.clone(), rate.quote.clone()))
+            .or_default();
+        let i = rates.partition_point(|other| other.valid_from <= rate.valid_from);
+        rates.insert(i, rate);
+    }
+
+    /// Rate for converting `from` to `to` valid at Unix `time`; see `now` for conversions
+    /// without time
+    pub fn rate(&self, from: &Currency, to: &Currency, time: u64) -> Option<&Rate> {
+        let valid = |base: &Currency, quote: &Currency| {
+            let rates = self.rates.get(&(base.clone(), quote.clone()))?;
+            rates.iter().rev().find(|rate| rate.valid_from <= time)
+        };
+        valid(from, to).or_else(|| valid(to, from))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        let table = RateTable::read(
+            &b"pair, rate, valid_from\n\
+               EUR/USD, 1.10, 100\n\
+               EUR/USD, 1.05, 50\n\
+               USD/JPY, 150, 100\n\
+               EUR/USD, 2.00, 18446744073709551615\n"[..],
+        )
+        .unwrap();
+        let (eur, usd, jpy) = (
+            Currency::new("EUR").unwrap(),
+            Currency::new("USD").unwrap(),
+            Currency::new("JPY").unwrap(),
+        );
+
+        let rate = |from, to, time| table.rate(from, to, time).map(|rate| rate.rate.to_string());
+        // Rate that isn't in effect yet isn't used
+        assert_eq!(rate(&eur, &usd, now()), Some("1.10".to_owned()));
+        assert_eq!(rate(&eur, &usd, u64::MAX), Some("2.00".to_owned()));
+        assert_eq!(rate(&eur, &usd, 99), Some("1.05".to_owned()));
+        assert_eq!(rate(&eur, &usd, 49), None);
+        assert_eq!(rate(&usd, &eur, 100), Some("1.10".to_owned()));
+        assert_eq!(rate(&eur, &jpy, now()), None);
+
+        let rate = table.rate(&jpy, &usd, now()).unwrap();
+        assert_eq!(
+            rate.convert(&jpy, Moneys::with_scale(1000, 0), 2, Rounding::HalfEven),
+            Ok(Moneys::with_scale(667, 2))
+        );
+        assert_eq!(
+            rate.convert(&usd, Moneys::with_scale(250, 2), 0, Rounding::HalfEven),
+            Ok(Moneys::with_scale(375, 0))
+        );
+
+        assert!(RateTable::read(&b"pair,rate,valid_from\nEURUSD,1.1,0\n"[..]).is_err());
+        assert!(RateTable::read(&b"pair,rate,valid_from\nEUR/USD,0,0\n"[..]).is_err());
+    }
+}
@@ -0,0 +1,120 @@
+//! Reporting of rejected commands
+
+use crate::io::Rejection;
+use anyhow::Result;
+use std::str::FromStr;
+
+/// Destination for commands refused by the processor
+pub trait RejectSink {
+    fn reject(&mut self, rejection: &Rejection) -> Result<()>;
+
+    /// Write out everything that might be buffered
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// File format of the rejection report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectFormat {
+    Csv,
+    /// JSON array of rejection objects
+    Json,
+}
+
+impl FromStr for RejectFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "csv" => Ok(RejectFormat::Csv),
+            "json" => Ok(RejectFormat::Json),
+            _ => Err(format!("unknown rejection report format {:?}", s)),
+        }
+    }
+}
+
+/// Writes rejections as CSV or JSON
+pub enum RejectWriter<W: std::io::Write> {
+    Csv(Box<csv::Writer<W>>),
+    Json { writer: W, empty: bool },
+}
+
+impl<W: std::io::Write> RejectWriter<W> {
+    pub fn new(raw_writer: W, format: RejectFormat) -> Self {
+        match format {
+            RejectFormat::Csv => RejectWriter::Csv(Box::new(csv::Writer::from_writer(raw_writer))),
+            RejectFormat::Json => RejectWriter::Json {
+                writer: raw_writer,
+                empty: true,
+            },
+        }
+    }
+}
+
+impl<W: std::io::Write> RejectSink for RejectWriter<W> {
+    fn reject(&mut self, rejection: &Rejection) -> Result<()> {
+        match self {
+            RejectWriter::Csv(writer) => writer.serialize(rejection)?,
+            RejectWriter::Json { writer, empty } => {
+                writer.write_all(if *empty { b"[\n" } else { b",\n" })?;
+                serde_json::to_writer(&mut *writer, rejection)?;
+                *empty = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        match self {
+            RejectWriter::Csv(writer) => writer.flush()?,
+            RejectWriter::Json { writer, empty } => {
+                writer.write_all(if *empty { b"[]\n" } else { b"\n]\n" })?;
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn rejection(line: u64) -> Rejection {
+        Rejection {
+            line,
+            row: "withdrawal, 1, 4, 1.5".to_owned(),
+            code: "insufficient_funds".to_owned(),
+            client: Some(1),
+            tx: Some(4),
+            message: "insufficient funds on account 1 (tx 4)".to_owned(),
+        }
+    }
+
+    fn write(format: RejectFormat, rejections: &[Rejection]) -> String {
+        let mut buffer = vec![];
+        let mut writer = RejectWriter::new(&mut buffer, format);
+        for rejection in rejections {
+            writer.reject(rejection).unwrap();
+        }
+        writer.finish().unwrap();
+        drop(writer);
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn csv() {
+        assert_eq!(
+            write(RejectFormat::Csv, &[rejection(5)]),
+            "line,row,code,client,tx,message\n\
+             5,\"withdrawal, 1, 4, 1.5\",insufficient_funds,1,4,insufficient funds on account 1 (tx 4)\n"
+        );
+    }
+
+    #[test]
+    fn json() {
+        assert_eq!(write(RejectFormat::Json, &[]), "[]\n");
+        let output = write(RejectFormat::Json, &[rejection(5), rejection(6)]);
+        let parsed: Vec<Rejection> = serde_json::from_str(&output).unwrap();
+        assert_eq!(parsed, vec![rejection(5), rejection(6)]);
+    }
+}
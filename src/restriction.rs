@@ -0,0 +1,160 @@
+//! Restrictions of what accounts accept
+//!
+//! An account can be restricted for several reasons at once, at most once for every reason, and
+//! a command has to be allowed by all of its restrictions. Which commands a restriction allows is
+//! up to the processor's `Policy`, the built-in ones follow `RestrictionPolicy`; admin commands
+//! are never restricted.
+
+use crate::io::CommandType;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Kind of restriction, what it allows is up to the `RestrictionPolicy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Restriction {
+    /// Nothing but settling disputes opened before
+    Frozen,
+    /// Money may come in, but not go out
+    DepositsOnly,
+    /// Anything but money going out
+    WithdrawalsBlocked,
+}
+
+/// Why an account is restricted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RestrictionReason {
+    /// Set by the processor on chargeback of a deposit, lifted by `unlock`
+    Chargeback,
+    /// Set by an operator
+    Manual,
+    /// Set by an operator for a compliance review
+    Compliance,
+}
+
+impl Restriction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Restriction::Frozen => "frozen",
+            Restriction::DepositsOnly => "deposits_only",
+            Restriction::WithdrawalsBlocked => "withdrawals_blocked",
+        }
+    }
+}
+
+impl RestrictionReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RestrictionReason::Chargeback => "chargeback",
+            RestrictionReason::Manual => "manual",
+            RestrictionReason::Compliance => "compliance",
+        }
+    }
+}
+
+impl fmt::Display for Restriction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Display for RestrictionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Restriction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "frozen" => Ok(Restriction::Frozen),
+            "deposits_only" => Ok(Restriction::DepositsOnly),
+            "withdrawals_blocked" => Ok(Restriction::WithdrawalsBlocked),
+            _ => Err(format!("unknown restriction {:?}", s)),
+        }
+    }
+}
+
+/// Command types every restriction allows. By default disputes opened before can always be
+/// resolved or charged back, so that held money doesn't get stuck:
+///
+/// * `frozen` allows only that
+/// * `deposits_only` allows deposits (and incoming transfers) too
+/// * `withdrawals_blocked` allows everything but withdrawals and outgoing transfers
+///
+/// Command changing also the account of another client (like a transfer) has to be allowed for
+/// both of them; for the recipient, a transfer counts as a deposit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestrictionPolicy {
+    allowed: HashMap<Restriction, Vec<CommandType>>,
+}
+
+impl Default for RestrictionPolicy {
+    fn default() -> Self {
+        use CommandType::*;
+        Self {
+            allowed: vec![
+                (Restriction::Frozen, vec![Resolve, Chargeback]),
+                (
+                    Restriction::DepositsOnly,
+                    vec![Deposit, Resolve, Chargeback],
+                ),
+                (
+                    Restriction::WithdrawalsBlocked,
+                    vec![Deposit, Dispute, Resolve, Chargeback, Convert],
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        }
+    }
+}
+
+impl RestrictionPolicy {
+    /// Policy under which restrictions allow nothing but admin commands
+    pub fn none() -> Self {
+        Self {
+            allowed: HashMap::new(),
+        }
+    }
+
+    /// Allow exactly `command_types` under `restriction`
+    pub fn allow(mut self, restriction: Restriction, command_types: &[CommandType]) -> Self {
+        self.allowed.insert(restriction, command_types.to_vec());
+        self
+    }
+
+    pub fn allows(&self, restriction: Restriction, command_type: CommandType) -> bool {
+        command_type.is_admin()
+            || self
+                .allowed
+                .get(&restriction)
+                .is_some_and(|allowed| allowed.contains(&command_type))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn policy() {
+        let policy = RestrictionPolicy::default();
+        assert!(policy.allows(Restriction::Frozen, CommandType::Resolve));
+        assert!(policy.allows(Restriction::Frozen, CommandType::Unfreeze));
+        assert!(!policy.allows(Restriction::Frozen, CommandType::Deposit));
+        assert!(policy.allows(Restriction::DepositsOnly, CommandType::Deposit));
+        assert!(!policy.allows(Restriction::DepositsOnly, CommandType::Convert));
+        assert!(policy.allows(Restriction::WithdrawalsBlocked, CommandType::Convert));
+        assert!(!policy.allows(Restriction::WithdrawalsBlocked, CommandType::Transfer));
+
+        let policy = policy.allow(Restriction::Frozen, &[]);
+        assert!(!policy.allows(Restriction::Frozen, CommandType::Resolve));
+        assert!(policy.allows(Restriction::Frozen, CommandType::Unlock));
+    }
+}
@@ -0,0 +1,306 @@
+//! HTTP interface to the processor
+//!
+//! Requests are handled by a few threads, but access to the processor is serialized by a
+//! mutex, so commands are executed one at a time in the order they got the lock.
+//!
+//! * `POST /transactions` executes a command given either as a JSON object (like
+//!   `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}`, amount as a string) or as a
+//!   CSV row (with `Content-Type: text/csv`, header is optional); responds with the new state
+//!   of the account in the currency of the command (in all currencies as an array for admin
+//!   commands), or with `422` and the reason of the rejection; commands of a batch aren't
+//!   supported
+//! * `GET /accounts/{client}` responds with the state of the account as a JSON array with an
+//!   object per currency
+//! * `GET /accounts` responds with all accounts as CSV, like the command-line tool prints them
+
+use crate::account::{Account, ClientId};
+use crate::error::TxError;
+use crate::input;
+use crate::io::Command;
+use crate::output::{print_accounts, OutputFormat};
+use crate::processor::{Processor, TransactionId};
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+const CSV: &str = "text/csv";
+const JSON: &str = "application/json";
+const TEXT: &str = "text/plain";
+
+/// Response to a request
+#[derive(Debug, PartialEq)]
+struct Reply {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Reply {
+    fn text(status: u16, body: impl Into<String>) -> Self {
+        Self {
+            status,
+            content_type: TEXT,
+            body: body.into(),
+        }
+    }
+
+    fn json<T: Serialize>(status: u16, value: &T) -> Self {
+        match serde_json::to_string(value) {
+            Ok(body) => Self {
+                status,
+                content_type: JSON,
+                body,
+            },
+            Err(error) => Self::text(500, error.to_string()),
+        }
+    }
+}
+
+/// Body of the response to a rejected command
+#[derive(Debug, Serialize)]
+struct Rejection {
+    /// Machine-readable reason (see `TxError::code`)
+    code: &'static str,
+    client: ClientId,
+    tx: TransactionId,
+    message: String,
+}
+
+impl Rejection {
+    fn new(command: &Command, error: &TxError) -> Self {
+        Self {
+            code: error.code(),
+            client: command.client,
+            tx: command.tx,
+            message: error.to_string(),
+        }
+    }
+}
+
+/// Parse command from request body
+fn parse_command(content_type: Option<&str>, body: &str) -> Result<Command> {
+    let is_csv = content_type.is_some_and(|content_type| content_type.starts_with(CSV));
+    if !is_csv {
+        return Ok(serde_json::from_str(body)?);
+    }
+    let body = body.trim_start();
+    let input = if body.starts_with("type") {
+        body.to_owned()
+    } else {
+        format!("{}\n{}", input::HEADER.join(","), body)
+    };
+    let mut rows = input::csv_rows(input.as_bytes())?;
+    match (rows.next(), rows.next()) {
+        (Some(row), None) => row?
+            .map(|row| row.command)
+            .map_err(|malformed| anyhow!(malformed.message)),
+        _ => Err(anyhow!("expected exactly one CSV row")),
+    }
+}
+
+fn handle(
+    processor: &Mutex<Processor>,
+    method: &str,
+    url: &str,
+    content_type: Option<&str>,
+    body: &str,
+) -> Reply {
+    let mut processor = match processor.lock() {
+        Ok(processor) => processor,
+        Err(_) => return Reply::text(500, "processor failed"),
+    };
+    let rows = |processor: &Processor, client| processor.account(client).map(Account::rows);
+
+    let path: Vec<_> = url.split('?').next().unwrap_or("").split('/').collect();
+    match (method, path.as_slice()) {
+        ("POST", ["", "transactions"]) => {
+            let command = match parse_command(content_type, body) {
+                Ok(command) => command,
+                Err(error) => return Reply::text(400, format!("invalid command: {}", error)),
+            };
+            if command.batch.is_some() {
+                return Reply::text(400, "batches aren't supported");
+            }
+            if let Err(error) = processor.execute(&command) {
+                return Reply::json(422, &Rejection::new(&command, &error));
+            }
+            // Disputes don't have to name the currency, but the transaction knows it
+            let currency = match processor.transaction(command.tx) {
+                Ok(Some(transaction)) => transaction.currency().cloned(),
+                Ok(None) => return Reply::text(500, "transaction of accepted command not found"),
+                Err(error) => return Reply::text(500, error.to_string()),
+            };
+            match (rows(&processor, command.client), currency) {
+                (Some(Ok(rows)), None) => Reply::json(200, &rows),
+                (Some(Ok(rows)), Some(currency)) => {
+                    match rows.iter().find(|row| row.currency == currency) {
+                        Some(row) => Reply::json(200, row),
+                        None => Reply::text(500, "balance of accepted command not found"),
+                    }
+                }
+                (Some(Err(error)), _) => Reply::text(500, error.to_string()),
+                (None, _) => Reply::text(500, "account of accepted command not found"),
+            }
+        }
+        ("GET", ["", "accounts"]) => {
+            let mut body = vec![];
+            match print_accounts(&mut body, &processor.accounts(), OutputFormat::Csv) {
+                Ok(()) => Reply {
+                    status: 200,
+                    content_type: CSV,
+                    body: String::from_utf8_lossy(&body).into_owned(),
+                },
+                Err(error) => Reply::text(500, error.to_string()),
+            }
+        }
+        ("GET", ["", "accounts", client]) => match client.parse() {
+            Err(_) => Reply::text(400, "invalid client ID"),
+            Ok(client) => match rows(&processor, client) {
+                Some(Ok(rows)) => Reply::json(200, &rows),
+                Some(Err(error)) => Reply::text(500, error.to_string()),
+                None => Reply::text(404, "account not found"),
+            },
+        },
+        (_, ["", "transactions"]) | (_, ["", "accounts"]) | (_, ["", "accounts", _]) => {
+            Reply::text(405, "method not allowed")
+        }
+        _ => Reply::text(404, "not found"),
+    }
+}
+
+/// Serve `processor` over HTTP on `address` (like `127.0.0.1:8080`) with `threads` threads.
+/// Doesn't return unless the server fails.
+pub fn serve(processor: Processor, address: &str, threads: usize, verbose: bool) -> Result<()> {
+    let server = tiny_http::Server::http(address)
+        .map_err(|error| anyhow!("failed listening on {}: {}", address, error))?;
+    let server = Arc::new(server);
+    let processor = Arc::new(Mutex::new(processor));
+
+    let workers: Vec<_> = (0..threads.max(1))
+        .map(|_| {
+            let server = Arc::clone(&server);
+            let processor = Arc::clone(&processor);
+            thread::spawn(move || -> std::io::Result<()> {
+                loop {
+                    let mut request = server.recv()?;
+                    let content_type = request
+                        .headers()
+                        .iter()
+                        .find(|header| header.field.equiv("Content-Type"))
+                        .map(|header| header.value.as_str().to_owned());
+                    let mut body = String::new();
+                    let reply = match request.as_reader().read_to_string(&mut body) {
+                        Ok(_) => handle(
+                            &processor,
+                            request.method().as_str(),
+                            request.url(),
+                            content_type.as_deref(),
+                            &body,
+                        ),
+                        Err(error) => Reply::text(400, error.to_string()),
+                    };
+                    if verbose {
+                        println!(
+                            "request: {} {} {:?}\nreply: {:?}",
+                            request.method(),
+                            request.url(),
+                            body,
+                            reply
+                        );
+                    }
+                    let header = tiny_http::Header::from_bytes("Content-Type", reply.content_type)
+                        .expect("valid header");
+                    let response = tiny_http::Response::from_string(reply.body)
+                        .with_status_code(reply.status)
+                        .with_header(header);
+                    // Client might have gone away already
+                    let _ = request.respond(response);
+                }
+            })
+        })
+        .collect();
+
+    for worker in workers {
+        worker
+            .join()
+            .map_err(|_| anyhow!("server thread panicked"))??;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::io;
+
+    #[test]
+    fn requests() {
+        let processor = Mutex::new(Processor::default());
+        let post =
+            |content_type, body| handle(&processor, "POST", "/transactions", content_type, body);
+        let get = |url| handle(&processor, "GET", url, None, "");
+
+        let reply = post(
+            None,
+            r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "2.5"}"#,
+        );
+        assert_eq!(reply.status, 200);
+        assert_eq!(
+            serde_json::from_str::<io::Account>(&reply.body).unwrap(),
+            serde_json::from_str::<io::Account>(
+                r#"{"client": 1, "available": "2.5", "held": "0", "total": "2.5", "locked": false}"#
+            )
+            .unwrap()
+        );
+        assert_eq!(post(Some("text/csv"), "deposit, 2, 2, 1.0\n").status, 200);
+        assert_eq!(
+            post(
+                Some("text/csv"),
+                "type,client,tx,amount\nwithdrawal,2,3,0.25"
+            )
+            .status,
+            200
+        );
+
+        let reply = post(Some("text/csv"), "withdrawal,1,4,3.0");
+        assert_eq!(reply.status, 422);
+        assert!(reply.body.contains("\"code\":\"insufficient_funds\""));
+        assert_eq!(post(None, "{").status, 400);
+        assert_eq!(
+            post(Some("text/csv"), "deposit,1,5,1\ndeposit,1,6,1").status,
+            400
+        );
+
+        assert_eq!(
+            get("/accounts"),
+            Reply {
+                status: 200,
+                content_type: CSV,
+                body: "client,available,held,total,locked\n\
+                       1,2.5000,0.0000,2.5000,false\n\
+                       2,0.7500,0.0000,0.7500,false\n"
+                    .to_owned()
+            }
+        );
+        let reply = get("/accounts/2");
+        assert_eq!(reply.status, 200);
+        assert!(reply.body.contains("\"available\":\"0.7500\""));
+        assert!(reply.body.starts_with('['));
+        assert_eq!(get("/accounts/3").status, 404);
+
+        // Accepted dispute replies with the balance in the currency of the transaction
+        assert_eq!(post(Some("text/csv"), "deposit,2,7,3,USD").status, 200);
+        let reply = post(None, r#"{"type": "dispute", "client": 2, "tx": 7}"#);
+        assert_eq!(reply.status, 200);
+        assert!(reply
+            .body
+            .contains("\"currency\":\"USD\",\"available\":\"0.0000\""));
+        assert_eq!(get("/accounts/x").status, 400);
+        assert_eq!(get("/nothing").status, 404);
+        assert_eq!(
+            handle(&processor, "DELETE", "/accounts", None, "").status,
+            405
+        );
+    }
+}
@@ -0,0 +1,25 @@
+//! Format of saved `Processor` state
+//!
+//! Snapshot is a sequence of JSON records, one per line. The first record is a header with
+//! format version, followed by all accounts and all transactions in no particular order.
+
+use crate::account::Account;
+use crate::processor::{Transaction, TransactionId};
+use serde::{Deserialize, Serialize};
+
+/// Version written into new snapshots, bumped whenever the format of accounts or transactions
+/// changes; snapshots of other versions are refused
+pub(crate) const VERSION: u32 = 2;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Record {
+    Header {
+        version: u32,
+    },
+    Account(Account),
+    Transaction {
+        tx: TransactionId,
+        transaction: Transaction,
+    },
+}
@@ -0,0 +1,197 @@
+//! Line-based socket interface to the processor
+//!
+//! Producers connect over TCP or a Unix domain socket and send CSV rows in the same format as
+//! the input file, one per line. The header line is optional, when sent first it names the
+//! columns of the following rows like in the input file. For every row the server answers
+//! with one line, in the same order:
+//!
+//! * `ack,<seq>` when the command was executed
+//! * `reject,<seq>,<code>,<message>` when it was refused, with the reason code from
+//!   `TxError::code` (or `malformed` for rows that couldn't be parsed, `unsupported` for rows
+//!   of a batch, since every row is answered on its own)
+//!
+//! `seq` is the sequence number of the row within the connection, starting from 1. Each
+//! connection is handled by its own thread and rows are executed in the order they were sent,
+//! while access to the processor is serialized, so producers can send all rows of a client over
+//! one connection and keep their order.
+
+use crate::input;
+use crate::processor::Processor;
+use anyhow::Result;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Code of rows belonging to a batch
+const UNSUPPORTED: &str = "unsupported";
+
+/// Execute rows read from `reader` and write answers to `writer`
+fn handle_connection<R: Read, W: Write>(
+    processor: &Mutex<Processor>,
+    reader: R,
+    writer: W,
+    verbose: bool,
+) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut writer = csv::WriterBuilder::new().flexible(true).from_writer(writer);
+    let mut seq: u64 = 0;
+    let mut header = input::default_header();
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        if line.trim().is_empty() {
+            continue;
+        }
+        if seq == 0 && input::is_csv_header(&line) {
+            header = input::csv_record(&line)?;
+            continue;
+        }
+        seq += 1;
+
+        let seq = seq.to_string();
+        match input::csv_row(&line, &header) {
+            Err(error) => {
+                writer.write_record([
+                    "reject",
+                    &seq,
+                    input::MALFORMED,
+                    &format!("malformed row: {}", error),
+                ])?;
+            }
+            Ok(command) if command.batch.is_some() => {
+                writer.write_record([
+                    "reject",
+                    &seq,
+                    UNSUPPORTED,
+                    "batches aren't supported over a socket",
+                ])?;
+            }
+            Ok(command) => {
+                let ret = processor
+                    .lock()
+                    .map_err(|_| anyhow::anyhow!("processor failed"))?
+                    .execute(&command);
+                if verbose {
+                    println!("command: {:?}\nresult: {:?}", command, ret);
+                }
+                match ret {
+                    Ok(()) => writer.write_record(["ack", &seq])?,
+                    Err(error) => {
+                        writer.write_record(["reject", &seq, error.code(), &error.to_string()])?
+                    }
+                }
+            }
+        }
+        // Answer right away, unless more rows are already waiting
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Handle every incoming connection in its own thread
+fn accept<S>(
+    processor: Processor,
+    incoming: impl Iterator<Item = io::Result<S>>,
+    verbose: bool,
+) -> Result<()>
+where
+    S: Send + 'static,
+    for<'a> &'a S: Read + Write,
+{
+    let processor = Arc::new(Mutex::new(processor));
+    for stream in incoming {
+        let stream = stream?;
+        let processor = Arc::clone(&processor);
+        thread::spawn(move || {
+            if let Err(error) = handle_connection(&processor, &stream, &stream, verbose) {
+                eprintln!("connection failed: {:#}", error);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Serve `processor` on TCP `address` (like `127.0.0.1:9000`). Doesn't return unless the server
+/// fails.
+pub fn serve_tcp(processor: Processor, address: &str, verbose: bool) -> Result<()> {
+    let listener = TcpListener::bind(address)?;
+    accept(processor, listener.incoming(), verbose)
+}
+
+/// Serve `processor` on Unix domain socket at `path`, replacing socket left there by a previous
+/// run. Doesn't return unless the server fails.
+#[cfg(unix)]
+pub fn serve_unix<P: AsRef<std::path::Path>>(
+    processor: Processor,
+    path: P,
+    verbose: bool,
+) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt as _;
+    use std::os::unix::net::UnixListener;
+
+    let path = path.as_ref();
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    accept(processor, listener.incoming(), verbose)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn protocol() {
+        let processor = Mutex::new(Processor::default());
+        let input = "type,client,tx,amount\n\
+                     deposit, 1, 1, 2.0\n\
+                     \n\
+                     withdrawal,1,2,3.0\n\
+                     deposit,x\n\
+                     withdrawal,1,3,1.5\n";
+        let mut output = vec![];
+        handle_connection(&processor, input.as_bytes(), &mut output, false).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[0], "ack,1");
+        assert_eq!(
+            lines[1],
+            "reject,2,insufficient_funds,insufficient funds on account 1 (tx 2)"
+        );
+        assert!(lines[2].starts_with("reject,3,malformed,"));
+        assert_eq!(lines[3], "ack,4");
+
+        // Header is optional, sequence numbers are per connection
+        let mut output = vec![];
+        let input = "deposit,2,5,1.0\n\
+                     deposit,2,6,1.0,,,,,payroll\n";
+        handle_connection(&processor, input.as_bytes(), &mut output, false).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines[0], "ack,1");
+        assert!(lines[1].starts_with("reject,2,unsupported,"));
+
+        // Columns are named by the header of the connection
+        let mut output = vec![];
+        let input = "client,type,amount,tx\n\
+                     3,deposit,2.0,7\n\
+                     3,withdrawal,1.5,8\n\
+                     3,withdrawal,1.0,9\n";
+        handle_connection(&processor, input.as_bytes(), &mut output, false).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(lines[..2], ["ack,1", "ack,2"]);
+        assert!(lines[2].starts_with("reject,3,insufficient_funds,"));
+    }
+}
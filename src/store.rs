@@ -0,0 +1,402 @@
+//! Storage of executed transactions
+
+use crate::processor::{Transaction, TransactionId};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// Every deposit and withdrawal has to be kept in case it's disputed later, the store decides
+/// where.
+pub trait TransactionStore: Send {
+    fn get(&mut self, tx: TransactionId) -> io::Result<Option<Transaction>>;
+
+    /// Store transaction that isn't in the store yet
+    fn insert(&mut self, tx: TransactionId, transaction: Transaction) -> io::Result<()>;
+
+    /// Replace transaction that is already in the store
+    fn update(&mut self, tx: TransactionId, transaction: Transaction) -> io::Result<()>;
+
+    /// Call `f` for every transaction in the store, in no particular order
+    fn for_each(
+        &mut self,
+        f: &mut dyn FnMut(TransactionId, Transaction) -> io::Result<()>,
+    ) -> io::Result<()>;
+}
+
+/// Keeps all transactions in memory
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStore {
+    transactions: HashMap<TransactionId, Transaction>,
+}
+
+impl TransactionStore for MemoryStore {
+    fn get(&mut self, tx: TransactionId) -> io::Result<Option<Transaction>> {
+        Ok(self.transactions.get(&tx).cloned())
+    }
+
+    fn insert(&mut self, tx: TransactionId, transaction: Transaction) -> io::Result<()> {
+        self.transactions.insert(tx, transaction);
+        Ok(())
+    }
+
+    fn update(&mut self, tx: TransactionId, transaction: Transaction) -> io::Result<()> {
+        self.transactions.insert(tx, transaction);
+        Ok(())
+    }
+
+    fn for_each(
+        &mut self,
+        f: &mut dyn FnMut(TransactionId, Transaction) -> io::Result<()>,
+    ) -> io::Result<()> {
+        for (tx, transaction) in &self.transactions {
+            f(*tx, transaction.clone())?;
+        }
+        Ok(())
+    }
+}
+
+/// Keeps transactions on disk in two files:
+///
+/// * append-only data file with records made of transaction ID, length and JSON-encoded
+///   transaction; updated transaction is appended as a new record
+/// * index file with offset of the latest record for every possible `TransactionId`, it's
+///   created as a sparse file, so it takes only as much disk space as there are transactions;
+///   its last slot tells whether the store was closed cleanly
+///
+/// Records are written out and synced to the disk before they are indexed. When the store wasn't
+/// closed cleanly, the data file is cut off at the first torn or unreadable record and the index
+/// is rebuilt from the data file on open, so that every index entry points to a record that
+/// made it to the disk.
+///
+/// Memory usage doesn't depend on number of transactions.
+#[derive(Debug)]
+pub struct FileStore {
+    data: BufWriter<fs::File>,
+    /// Length of the data file, including what's still buffered
+    data_len: u64,
+    index: fs::File,
+}
+
+impl FileStore {
+    /// Data file starts with this, so that zero offset in the index can mean "no record"
+    const MAGIC: &'static [u8; 8] = b"TXSTORE1";
+    const INDEX_SLOT_SIZE: u64 = 8;
+    const RECORD_HEADER_SIZE: u64 = 8;
+    const DATA_FILE: &'static str = "transactions.dat";
+    const INDEX_FILE: &'static str = "transactions.idx";
+    /// Slot after the one of the last `TransactionId`
+    const STATE_SLOT: u64 = (TransactionId::MAX as u64 + 1) * Self::INDEX_SLOT_SIZE;
+    /// Value of the state slot after the store was closed cleanly
+    const CLOSED: u64 = 1;
+
+    /// Open store in directory `dir`, creating it if it doesn't exist yet
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let open = |name| {
+            fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(dir.join(name))
+        };
+        let mut data = open(Self::DATA_FILE)?;
+        let index = open(Self::INDEX_FILE)?;
+
+        let mut data_len = data.metadata()?.len();
+        if data_len == 0 {
+            data.write_all(Self::MAGIC)?;
+            data_len = Self::MAGIC.len() as u64;
+        } else {
+            let mut magic = [0; 8];
+            data.read_exact(&mut magic)?;
+            if &magic != Self::MAGIC {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a transaction store data file",
+                ));
+            }
+        }
+        let index_len = Self::STATE_SLOT + Self::INDEX_SLOT_SIZE;
+        if index.metadata()?.len() < index_len {
+            index.set_len(index_len)?;
+        }
+        data.seek(SeekFrom::End(0))?;
+
+        let mut store = Self {
+            data: BufWriter::new(data),
+            data_len,
+            index,
+        };
+        let closed = store.read_slot(Self::STATE_SLOT)? == Self::CLOSED;
+        store.write_slot(Self::STATE_SLOT, 0)?;
+        if !closed {
+            store.recover()?;
+        }
+        Ok(store)
+    }
+
+    /// Cut off the data file at the first incomplete or unreadable record (the rest was written
+    /// after it, so it can't be trusted either) and index the readable ones again
+    fn recover(&mut self) -> io::Result<()> {
+        let index_len = self.index.metadata()?.len();
+        self.index.set_len(0)?;
+        self.index.set_len(index_len)?;
+
+        let file = self.data.get_mut();
+        let mut reader = io::BufReader::new(file.try_clone()?);
+        let mut offset = reader.seek(SeekFrom::Start(Self::MAGIC.len() as u64))?;
+        let mut records = vec![];
+        loop {
+            match Self::read_record_header(&mut reader) {
+                Ok((tx, len)) if offset + Self::RECORD_HEADER_SIZE + len <= self.data_len => {
+                    let mut record = vec![0; len as usize];
+                    reader.read_exact(&mut record)?;
+                    if serde_json::from_slice::<Transaction>(&record).is_err() {
+                        break;
+                    }
+                    records.push((tx, offset));
+                    offset += Self::RECORD_HEADER_SIZE + len;
+                }
+                Ok(_) => break,
+                Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(error) => return Err(error),
+            }
+        }
+        file.set_len(offset)?;
+        file.seek(SeekFrom::End(0))?;
+        self.data_len = offset;
+        for (tx, offset) in records {
+            self.write_offset(tx, offset)?;
+        }
+        Ok(())
+    }
+
+    fn read_slot(&mut self, position: u64) -> io::Result<u64> {
+        let mut value = [0; 8];
+        self.index.seek(SeekFrom::Start(position))?;
+        self.index.read_exact(&mut value)?;
+        Ok(u64::from_le_bytes(value))
+    }
+
+    fn write_slot(&mut self, position: u64, value: u64) -> io::Result<()> {
+        self.index.seek(SeekFrom::Start(position))?;
+        self.index.write_all(&value.to_le_bytes())
+    }
+
+    fn read_offset(&mut self, tx: TransactionId) -> io::Result<u64> {
+        self.read_slot(u64::from(tx) * Self::INDEX_SLOT_SIZE)
+    }
+
+    fn write_offset(&mut self, tx: TransactionId, offset: u64) -> io::Result<()> {
+        self.write_slot(u64::from(tx) * Self::INDEX_SLOT_SIZE, offset)
+    }
+
+    fn append(&mut self, tx: TransactionId, transaction: &Transaction) -> io::Result<()> {
+        let record = serde_json::to_vec(transaction)?;
+        let offset = self.data_len;
+        self.data.write_all(&tx.to_le_bytes())?;
+        self.data.write_all(&(record.len() as u32).to_le_bytes())?;
+        self.data.write_all(&record)?;
+        self.data_len += Self::RECORD_HEADER_SIZE + record.len() as u64;
+        // Index must not point to a record that isn't on the disk yet
+        self.data.flush()?;
+        self.data.get_ref().sync_data()?;
+        self.write_offset(tx, offset)
+    }
+
+    /// Read transaction ID and length of the record at current position of the data file
+    fn read_record_header<R: Read>(reader: &mut R) -> io::Result<(TransactionId, u64)> {
+        let mut header = [0; Self::RECORD_HEADER_SIZE as usize];
+        reader.read_exact(&mut header)?;
+        let tx = TransactionId::from_le_bytes([header[0], header[1], header[2], header[3]]);
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        Ok((tx, u64::from(len)))
+    }
+
+    /// Read record from current position of the data file
+    fn read_record<R: Read>(reader: &mut R) -> io::Result<(TransactionId, Transaction, u64)> {
+        let (tx, len) = Self::read_record_header(reader)?;
+        let mut record = vec![0; len as usize];
+        reader.read_exact(&mut record)?;
+        let transaction = serde_json::from_slice(&record)?;
+        Ok((tx, transaction, Self::RECORD_HEADER_SIZE + len))
+    }
+}
+
+impl Drop for FileStore {
+    fn drop(&mut self) {
+        // Without the mark, the next open checks the files
+        if self.data.flush().is_ok() {
+            let _ = self.write_slot(Self::STATE_SLOT, Self::CLOSED);
+        }
+    }
+}
+
+impl TransactionStore for FileStore {
+    fn get(&mut self, tx: TransactionId) -> io::Result<Option<Transaction>> {
+        let offset = self.read_offset(tx)?;
+        if offset == 0 {
+            return Ok(None);
+        }
+        self.data.flush()?;
+        let file = self.data.get_mut();
+        file.seek(SeekFrom::Start(offset))?;
+        let (_, transaction, _) = Self::read_record(file)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Some(transaction))
+    }
+
+    fn insert(&mut self, tx: TransactionId, transaction: Transaction) -> io::Result<()> {
+        self.append(tx, &transaction)
+    }
+
+    fn update(&mut self, tx: TransactionId, transaction: Transaction) -> io::Result<()> {
+        self.append(tx, &transaction)
+    }
+
+    fn for_each(
+        &mut self,
+        f: &mut dyn FnMut(TransactionId, Transaction) -> io::Result<()>,
+    ) -> io::Result<()> {
+        self.data.flush()?;
+        let mut reader = io::BufReader::new(self.data.get_ref().try_clone()?);
+        let mut offset = reader.seek(SeekFrom::Start(Self::MAGIC.len() as u64))?;
+        while offset < self.data_len {
+            let (tx, transaction, len) = Self::read_record(&mut reader)?;
+            // Only the latest record of every transaction counts
+            if self.read_offset(tx)? == offset {
+                f(tx, transaction)?;
+            }
+            offset += len;
+        }
+        self.data.get_mut().seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::processor::DisputedAmounts;
+    use crate::Moneys;
+
+    fn deposit(client: u16, units: u128) -> Transaction {
+        Transaction::DepositTransaction {
+            client,
+            amount: Moneys::new(units),
+            currency: Default::default(),
+            disputes: DisputedAmounts::NONE,
+        }
+    }
+
+    fn exercise(store: &mut dyn TransactionStore) {
+        assert_eq!(store.get(1).unwrap(), None);
+        store.insert(1, deposit(1, 100)).unwrap();
+        store.insert(TransactionId::MAX, deposit(2, 200)).unwrap();
+        assert_eq!(store.get(1).unwrap(), Some(deposit(1, 100)));
+        store.update(1, deposit(1, 50)).unwrap();
+        assert_eq!(store.get(1).unwrap(), Some(deposit(1, 50)));
+        assert_eq!(
+            store.get(TransactionId::MAX).unwrap(),
+            Some(deposit(2, 200))
+        );
+        assert_eq!(store.get(2).unwrap(), None);
+
+        let mut all = vec![];
+        store
+            .for_each(&mut |tx, transaction| {
+                all.push((tx, transaction));
+                Ok(())
+            })
+            .unwrap();
+        all.sort_by_key(|(tx, _)| *tx);
+        assert_eq!(
+            all,
+            vec![(1, deposit(1, 50)), (TransactionId::MAX, deposit(2, 200))]
+        );
+    }
+
+    #[test]
+    fn memory() {
+        exercise(&mut MemoryStore::default());
+    }
+
+    #[test]
+    fn file() {
+        let dir = tempfile::tempdir().unwrap();
+        exercise(&mut FileStore::open(dir.path()).unwrap());
+
+        // Reopened store sees everything written before
+        let mut store = FileStore::open(dir.path()).unwrap();
+        assert_eq!(store.get(1).unwrap(), Some(deposit(1, 50)));
+        store.insert(3, deposit(3, 300)).unwrap();
+        assert_eq!(store.get(3).unwrap(), Some(deposit(3, 300)));
+    }
+
+    #[test]
+    fn file_not_closed() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = FileStore::open(dir.path()).unwrap();
+        store.insert(1, deposit(1, 100)).unwrap();
+        store.update(1, deposit(1, 50)).unwrap();
+        let data_len = store.data_len;
+        // Crash in the middle of writing record of transaction 2, after it was indexed
+        store.insert(2, deposit(2, 200)).unwrap();
+        let torn_len = data_len + 5;
+        store.data.get_ref().set_len(torn_len).unwrap();
+        store.write_offset(3, torn_len + 100).unwrap();
+        std::mem::forget(store);
+
+        let mut store = FileStore::open(dir.path()).unwrap();
+        assert_eq!(store.data_len, data_len);
+        assert_eq!(
+            fs::metadata(dir.path().join(FileStore::DATA_FILE))
+                .unwrap()
+                .len(),
+            data_len
+        );
+        assert_eq!(store.get(1).unwrap(), Some(deposit(1, 50)));
+        assert_eq!(store.get(2).unwrap(), None);
+        assert_eq!(store.get(3).unwrap(), None);
+        store.insert(2, deposit(2, 20)).unwrap();
+        drop(store);
+
+        let mut store = FileStore::open(dir.path()).unwrap();
+        assert_eq!(store.get(2).unwrap(), Some(deposit(2, 20)));
+        let mut count = 0;
+        store
+            .for_each(&mut |_, _| {
+                count += 1;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn file_unreadable_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = FileStore::open(dir.path()).unwrap();
+        store.insert(1, deposit(1, 100)).unwrap();
+        let data_len = store.data_len;
+        store.insert(2, deposit(2, 200)).unwrap();
+        store.insert(3, deposit(3, 300)).unwrap();
+        // Crash after the file grew but before the payload of transaction 2 reached the disk
+        let mut file = store.data.get_ref();
+        file.seek(SeekFrom::Start(data_len + FileStore::RECORD_HEADER_SIZE))
+            .unwrap();
+        file.write_all(&[0; 4]).unwrap();
+        std::mem::forget(store);
+
+        let mut store = FileStore::open(dir.path()).unwrap();
+        assert_eq!(store.data_len, data_len);
+        assert_eq!(store.get(1).unwrap(), Some(deposit(1, 100)));
+        assert_eq!(store.get(2).unwrap(), None);
+        assert_eq!(store.get(3).unwrap(), None);
+        store.insert(2, deposit(2, 20)).unwrap();
+        assert_eq!(store.get(2).unwrap(), Some(deposit(2, 20)));
+    }
+}
@@ -0,0 +1,152 @@
+//! Asynchronous interface to the processor
+
+use crate::account::Account;
+use crate::error::TxError;
+use crate::io::Command;
+use crate::processor::Processor;
+use anyhow::{anyhow, Result};
+use futures::{stream, Stream, StreamExt};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// How many commands and outcomes may wait between the caller and the executing task
+const QUEUE_LENGTH: usize = 64;
+
+/// Result of a single command executed by `Processor::run_stream`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /// Command was executed, `account` is the new state of the client's account
+    Accepted {
+        command: Command,
+        account: Account,
+    },
+    Rejected {
+        command: Command,
+        error: TxError,
+    },
+}
+
+impl Processor {
+    /// Execute commands as they come from `commands`, yielding an outcome for every one of them
+    /// in the same order. Has to be called within a tokio runtime: commands are passed over a
+    /// channel to a blocking task, so that the store and the journal don't stall the runtime.
+    /// The processor is locked for every command only, so it can be shared with other tasks
+    /// meanwhile. If the processor fails, the stream ends with the error.
+    pub fn run_stream<S>(
+        processor: Arc<Mutex<Processor>>,
+        commands: S,
+    ) -> impl Stream<Item = Result<Outcome>> + Send + 'static
+    where
+        S: Stream<Item = Command> + Send + 'static,
+    {
+        let (command_sender, mut command_receiver) = mpsc::channel(QUEUE_LENGTH);
+        let (outcome_sender, outcome_receiver) = mpsc::channel(QUEUE_LENGTH);
+        tokio::spawn(async move {
+            let mut commands = Box::pin(commands);
+            while let Some(command) = commands.next().await {
+                // Executing task stops when the outcomes aren't wanted anymore
+                if command_sender.send(command).await.is_err() {
+                    break;
+                }
+            }
+        });
+        tokio::task::spawn_blocking(move || {
+            while let Some(command) = command_receiver.blocking_recv() {
+                let outcome = execute(&processor, command);
+                let failed = outcome.is_err();
+                if outcome_sender.blocking_send(outcome).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        stream::unfold(outcome_receiver, |mut receiver| async move {
+            let outcome = receiver.recv().await?;
+            Some((outcome, receiver))
+        })
+    }
+}
+
+fn execute(processor: &Mutex<Processor>, command: Command) -> Result<Outcome> {
+    let mut processor = processor.lock().map_err(|_| anyhow!("processor failed"))?;
+    Ok(match processor.execute(&command) {
+        Ok(()) => {
+            let account = processor.account(command.client).cloned().ok_or_else(|| {
+                anyhow!(
+                    "account {} is missing after accepted command (tx {})",
+                    command.client,
+                    command.tx
+                )
+            })?;
+            Outcome::Accepted { command, account }
+        }
+        Err(error) => Outcome::Rejected { command, error },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::currency::Currency;
+    use crate::io::CommandType;
+    use crate::moneys::Moneys;
+
+    fn command(command_type: CommandType, client: u16, tx: u32, amount: &str) -> Command {
+        Command::new(command_type, client, tx).amount(amount)
+    }
+
+    #[tokio::test]
+    async fn outcomes() {
+        let commands = vec![
+            command(CommandType::Deposit, 1, 1, "2.0"),
+            command(CommandType::Withdrawal, 1, 2, "3.0"),
+            command(CommandType::Withdrawal, 1, 3, "0.5"),
+        ];
+        let processor = Arc::new(Mutex::new(Processor::default()));
+
+        // Commands come from a tokio channel, outcomes are collected by a spawned task
+        let (sender, receiver) = mpsc::channel(1);
+        let receiver = stream::unfold(receiver, |mut receiver| async move {
+            let command = receiver.recv().await?;
+            Some((command, receiver))
+        });
+        let outcomes = Processor::run_stream(processor.clone(), receiver);
+        let collector = tokio::spawn(outcomes.collect::<Vec<_>>());
+        for command in commands.clone() {
+            sender.send(command).await.unwrap();
+        }
+        drop(sender);
+        let outcomes = collector
+            .await
+            .unwrap()
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        let mut account = Account::new(1);
+        account = account
+            .deposit(&Currency::default(), Moneys::new(20_000))
+            .unwrap();
+        let after_deposit = account.clone();
+        account = account
+            .withdraw(&Currency::default(), Moneys::new(5_000))
+            .unwrap();
+        assert_eq!(
+            outcomes,
+            vec![
+                Outcome::Accepted {
+                    command: commands[0].clone(),
+                    account: after_deposit,
+                },
+                Outcome::Rejected {
+                    command: commands[1].clone(),
+                    error: TxError::InsufficientFunds { client: 1, tx: 2 },
+                },
+                Outcome::Accepted {
+                    command: commands[2].clone(),
+                    account: account.clone(),
+                },
+            ]
+        );
+        assert_eq!(processor.lock().unwrap().accounts(), vec![account]);
+    }
+}
@@ -1,7 +1,10 @@
 use anyhow::{bail, Context as _, Result};
 use std::fs;
 use std::io;
-use transaction_processor::{io::Account as IoAccount, run_processor, Processor};
+use transaction_processor::{
+    io::Account as IoAccount, io::Rejection, run_parallel, run_processor, Account, FileStore,
+    Processor, RejectSink,
+};
 
 /// Utility function to read accounts for testing purposes
 pub fn read_accounts(path: &str) -> Result<Vec<IoAccount>> {
@@ -20,19 +23,46 @@ pub fn read_accounts(path: &str) -> Result<Vec<IoAccount>> {
 }
 
 fn sort_clients(a: &IoAccount, b: &IoAccount) -> std::cmp::Ordering {
-    a.client.partial_cmp(&b.client).unwrap()
+    (a.client, &a.currency).cmp(&(b.client, &b.currency))
 }
 
-fn run_testcase(transaction_path: &str, account_path: &str) -> Result<()> {
+/// Collects rejections in memory
+#[derive(Default)]
+struct Rejections(Vec<Rejection>);
+
+impl RejectSink for Rejections {
+    fn reject(&mut self, rejection: &Rejection) -> Result<()> {
+        self.0.push(rejection.clone());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn run_testcase(
+    mut processor: Processor,
+    transaction_path: &str,
+    account_path: &str,
+) -> Result<()> {
     let f = fs::File::open(transaction_path)?;
     let raw_reader = io::BufReader::new(f);
-    let mut processor: Processor = Default::default();
-    run_processor(&mut processor, raw_reader, true).context("error in transaction runner")?;
-    let mut accounts: Vec<_> = processor
-        .accounts()
-        .into_iter()
-        .map(|account| IoAccount::from(account))
-        .collect();
+    run_processor(&mut processor, raw_reader, None, true, None, None)
+        .context("error in transaction runner")?;
+    check_accounts(processor.accounts(), transaction_path, account_path)
+}
+
+fn check_accounts(
+    accounts: Vec<Account>,
+    transaction_path: &str,
+    account_path: &str,
+) -> Result<()> {
+    let mut rows = vec![];
+    for account in &accounts {
+        rows.extend(account.rows()?);
+    }
+    let mut accounts = rows;
     let mut expected_accounts = read_accounts(account_path)?;
 
     accounts.sort_by(sort_clients);
@@ -42,7 +72,6 @@ fn run_testcase(transaction_path: &str, account_path: &str) -> Result<()> {
     // checking the whole list
     let mut fail = false;
     for i in 0..accounts.len().max(expected_accounts.len()) {
-        // TODO: this test needs to test equality on floats with limited precision (approx_eq)
         if accounts[i] != expected_accounts[i] {
             println!(
                 "accounts at position {} differ:\n{:?}\n{:?}",
@@ -58,12 +87,73 @@ fn run_testcase(transaction_path: &str, account_path: &str) -> Result<()> {
     Ok(())
 }
 
+const PREFIX: &str = "tests/test-cases";
+const TESTCASES: &[&str] = &[
+    "test1",
+    "official",
+    "withdrawal-disputes",
+    "partial-disputes",
+    "shared-tx-ids",
+    "ndjson",
+    "currencies",
+    "transfers",
+    "batches",
+    "admin",
+    "restrictions",
+];
+
 #[test]
 fn run_all_testcases() {
-    const PREFIX: &str = "tests/test-cases";
-    for name in ["test1", "official"].iter() {
+    for name in TESTCASES.iter() {
         let tp = format!("{}/{}.input.txt", PREFIX, name);
         let ap = format!("{}/{}.output.txt", PREFIX, name);
-        run_testcase(&tp, &ap).unwrap();
+        run_testcase(Processor::default(), &tp, &ap).unwrap();
+    }
+}
+
+#[test]
+fn run_all_testcases_with_file_store() {
+    for name in TESTCASES.iter() {
+        let tp = format!("{}/{}.input.txt", PREFIX, name);
+        let ap = format!("{}/{}.output.txt", PREFIX, name);
+        let dir = tempfile::tempdir().unwrap();
+        let store = FileStore::open(dir.path()).unwrap();
+        let processor = Processor::builder().store(Box::new(store)).build();
+        run_testcase(processor, &tp, &ap).unwrap();
+    }
+}
+
+#[test]
+fn run_all_testcases_in_parallel() {
+    for name in TESTCASES.iter() {
+        let tp = format!("{}/{}.input.txt", PREFIX, name);
+        let ap = format!("{}/{}.output.txt", PREFIX, name);
+
+        let mut expected_rejections = Rejections::default();
+        let mut processor = Processor::default();
+        let f = fs::File::open(&tp).unwrap();
+        run_processor(
+            &mut processor,
+            f,
+            None,
+            false,
+            Some(&mut expected_rejections),
+            None,
+        )
+        .unwrap();
+        expected_rejections.finish().unwrap();
+
+        let mut rejections = Rejections::default();
+        let processors = (0..3).map(|_| Processor::default()).collect();
+        let f = fs::File::open(&tp).unwrap();
+        let processors = run_parallel(processors, f, None, false, Some(&mut rejections)).unwrap();
+        rejections.finish().unwrap();
+
+        let accounts = processors
+            .iter()
+            .flat_map(|processor| processor.accounts())
+            .collect();
+        check_accounts(accounts, &tp, &ap).unwrap();
+        assert_eq!(rejections.0, expected_rejections.0, "{}", name);
     }
 }